@@ -5,5 +5,6 @@ cd `dirname $0`/..
 cargo build --release --all
 ./scripts/osx_vst_bundler.sh Pendulum target/release/libpendulum.dylib
 ./scripts/osx_vst_bundler.sh Fermi target/release/libfermi.dylib
+./scripts/osx_vst_bundler.sh Delay target/release/libdelay.dylib
 
 ./scripts/test.sh
@@ -0,0 +1,13 @@
+#[macro_use] extern crate vst2;
+extern crate surgemachine_plugin_base as base;
+
+use base::{SynthPlugin, SynthPluginData, DeviceType};
+
+type DelayPlugin = SynthPlugin<DelayPluginData>;
+
+struct DelayPluginData;
+impl SynthPluginData for DelayPluginData {
+    fn get_device_type () -> DeviceType { DeviceType::Delay }
+}
+
+plugin_main!(DelayPlugin);
@@ -1,18 +1,37 @@
 use vst2::buffer::AudioBuffer;
-use vst2::plugin::{Category, Plugin, Info, CanDo};
+use vst2::plugin::{Category, Plugin, Info, CanDo, HostCallback};
+use vst2::host::Host;
 use vst2::event::Event;
 use vst2::api::Supported;
 use std::marker::PhantomData;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::cmp;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Read;
+use std::os::raw::{c_char, c_void};
+use std::path::Path;
+use std::sync::Arc;
 
 use surgemachine::create_device;
+use surgemachine::cc_map::MappedDevice;
 pub use surgemachine::DeviceType;
-use surgemachine::device::DevicePlugin;
+use surgemachine::device::{DevicePlugin, TimedEvent};
+use surgemachine::midi::MidiMessage;
+use surgemachine::wavetable::Wavetable;
+
+// `kVstTempoValid` of the time info flags
+const TEMPO_VALID: i32 = 1 << 10;
+// "SMwt", vendor specific call loading a WAV file as an oscillator's wavetable.
+// `value` is the oscillator and `ptr` the file's path, a null terminated UTF-8 string
+const LOAD_WAVETABLE: i32 = 0x534D_7774;
 
 pub struct SynthPlugin<Data: SynthPluginData> {
+    host: HostCallback,
     sample_rate: f32,
     device: Option<Box<DevicePlugin>>,
+    events: Vec<TimedEvent>,
     phantom: PhantomData<Data>
 }
 
@@ -28,30 +47,61 @@ pub trait SynthPluginData {
 
 impl<Data: SynthPluginData> SynthPlugin<Data> {
 
-    /// http://www.midimountain.com/midi/midi_status.htm
-    fn process_midi_event(&mut self, data: [u8; 3]) {
-        self.device.as_mut()
-            .map(|d| {
-                match data[0] {
-                    128 => d.note_off(data[1], data[2]),
-                    144 => d.note_on(data[1], data[2]),
-                    _ => ()
-                };
-            });
+    fn process_midi_event(&mut self, data: [u8; 3], delta_frames: i32) {
+        if let Some(message) = MidiMessage::parse(&data) {
+            // the buffer is allocated up front, events past its capacity are dropped rather
+            // than growing it on the audio thread
+            if self.events.len() < self.events.capacity() {
+                self.events.push(TimedEvent {
+                    offset: cmp::max(0, delta_frames) as usize,
+                    message: message,
+                });
+            }
+        }
+    }
+
+    /// Replaces the table of oscillator `osc` with the WAV file at `path`. Tables are saved
+    /// with the plugin state, the file is not needed once loaded.
+    pub fn load_wavetable (&mut self, osc: usize, path: &Path) -> bool {
+        let mut data = Vec::new();
+        let table = File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut data)).ok()
+            .and_then(|_| Wavetable::from_wav(&data).ok());
+        match (table, self.device.as_mut()) {
+            (Some(table), Some(dev)) => {
+                dev.set_wavetable(osc, Some(Arc::new(table)));
+                true
+            },
+            _ => false,
+        }
     }
 
     fn init_device (&mut self) {
-        let mut device = create_device(Data::get_device_type());
+        let mut device: Box<DevicePlugin> = Box::new(MappedDevice::new(create_device(Data::get_device_type())));
         device.set_sample_rate(self.sample_rate);
         self.device = Some(device)
     }
 }
 
+/// Stable insertion sort, events sharing an offset keep their arrival order. Runs in place,
+/// and in a single pass when the events already arrive in order.
+fn sort_by_offset (events: &mut [TimedEvent]) {
+    for i in 1..events.len() {
+        let mut j = i;
+        while j > 0 && events[j - 1].offset > events[j].offset {
+            events.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+}
+
 impl<D: SynthPluginData> Default for SynthPlugin<D> {
     fn default() -> Self {
         let mut plugin = Self {
+            host: Default::default(),
             sample_rate: 44100.0,
             device: None,
+            events: Vec::with_capacity(256),
             phantom: Default::default()
         };
         plugin.init_device();
@@ -60,16 +110,24 @@ impl<D: SynthPluginData> Default for SynthPlugin<D> {
 }
 
 impl<Data: SynthPluginData> Plugin for SynthPlugin<Data> {
+    fn new(host: HostCallback) -> Self {
+        let mut plugin = Self::default();
+        plugin.host = host;
+        plugin
+    }
+
     fn get_info(&self) -> Info {
+        let effect = Data::get_device_type().is_effect();
         Info {
             name: Data::get_name(),
             vendor: "Frizi".to_string(),
             unique_id: Data::get_uid(),
-            category: Category::Synth,
-            inputs: 0,
+            category: if effect { Category::Effect } else { Category::Synth },
+            inputs: if effect { 2 } else { 0 },
             outputs: 2,
             parameters: self.device.as_ref().map_or(0, |d| d.get_num_parameters()),
             initial_delay: 0,
+            preset_chunks: true,
             ..Info::default()
         }
     }
@@ -77,7 +135,7 @@ impl<Data: SynthPluginData> Plugin for SynthPlugin<Data> {
     fn process_events(&mut self, events: Vec<Event>) {
         for event in events {
             match event {
-                Event::Midi { data, ..  } => self.process_midi_event(data),
+                Event::Midi { data, delta_frames, ..  } => self.process_midi_event(data, delta_frames),
                 // More events can be handled here.
                 _ => {}
             }
@@ -110,6 +168,11 @@ impl<Data: SynthPluginData> Plugin for SynthPlugin<Data> {
             .map_or(format!("{:.3}", value), |dev| dev.get_parameter_text(param))
     }
 
+    fn can_be_automated(&self, param: i32) -> bool {
+        self.device.as_ref()
+            .map_or(false, |dev| dev.can_be_automated(param))
+    }
+
     fn set_sample_rate(&mut self, rate: f32) {
         self.sample_rate = rate;
         self.device.as_mut()
@@ -117,13 +180,58 @@ impl<Data: SynthPluginData> Plugin for SynthPlugin<Data> {
     }
 
     fn process(&mut self, buffer: AudioBuffer<f32>) {
-        let (_, mut outputs) = buffer.split();
+        let (mut inputs, mut outputs) = buffer.split();
 
         if let Some(dev) = self.device.as_mut() {
+            if let Some(info) = self.host.get_time_info(TEMPO_VALID) {
+                if info.flags & TEMPO_VALID != 0 {
+                    dev.set_tempo(info.tempo as f32);
+                }
+            }
             if outputs.len() < 2 { panic!("Outputs should have at least length 2") }
             let right = outputs.remove(1);
             let left = outputs.remove(0);
-            dev.run(None, Some([right, left]))
+            // inputs keep the same channel order as the outputs
+            let inputs = if inputs.len() >= 2 {
+                let right = inputs.remove(1);
+                let left = inputs.remove(0);
+                Some([right, left])
+            } else {
+                None
+            };
+            // hosts should deliver events in order, but nothing enforces it
+            sort_by_offset(&mut self.events);
+            dev.run(&self.events, inputs, Some([right, left]))
+        }
+        self.events.clear();
+    }
+
+    fn get_preset_data(&mut self) -> Vec<u8> {
+        self.device.as_ref()
+            .map_or(Vec::new(), |dev| dev.get_state())
+    }
+
+    fn get_bank_data(&mut self) -> Vec<u8> {
+        self.get_preset_data()
+    }
+
+    fn load_preset_data(&mut self, data: &[u8]) {
+        self.device.as_mut()
+            .map(|dev| dev.set_state(data));
+    }
+
+    fn load_bank_data(&mut self, data: &[u8]) {
+        self.load_preset_data(data)
+    }
+
+    fn vendor_specific(&mut self, index: i32, value: isize, ptr: *mut c_void, _opt: f32) -> isize {
+        if index != LOAD_WAVETABLE || value < 0 || ptr.is_null() {
+            return 0;
+        }
+        let path = unsafe { CStr::from_ptr(ptr as *const c_char) };
+        match path.to_str() {
+            Ok(path) => self.load_wavetable(value as usize, Path::new(path)) as isize,
+            Err(_) => 0,
         }
     }
 
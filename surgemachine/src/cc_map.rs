@@ -0,0 +1,487 @@
+use device::{self, AudioBus, Device, DevicePlugin, TimedEvent};
+use midi::MidiMessage;
+use state::{StateReader, StateWriter};
+use std::cmp;
+use std::sync::Arc;
+use wavetable::Wavetable;
+use IndexedEnum;
+
+const NUM_CONTROLLERS: usize = 128;
+const STATE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CcMapping {
+    pub param: i32,
+    pub min: f32,
+    pub max: f32,
+    pub invert: bool,
+}
+
+impl CcMapping {
+    pub fn new (param: i32) -> Self {
+        Self {
+            param: param,
+            min: 0.0,
+            max: 1.0,
+            invert: false,
+        }
+    }
+
+    pub fn value (&self, cc_value: u8) -> f32 {
+        let unit = cc_value as f32 / 127.0;
+        let unit = if self.invert { 1.0 - unit } else { unit };
+        self.min + (self.max - self.min) * unit
+    }
+}
+
+/// Parameters appended after those of the wrapped device, so CCs can be learned from the host.
+/// Min, max and invert edit the mapping of the parameter currently selected as learn target.
+/// None of them can be automated, nor are they saved with the state.
+#[derive(Debug, Clone, Copy, IndexedEnum)]
+pub enum LearnParams {
+    LearnTarget,
+    LearnMin,
+    LearnMax,
+    LearnInvert,
+}
+
+/// Maps incoming control changes to parameters of the wrapped device.
+/// Mapped CCs are not forwarded to the device, which renders the block in segments
+/// so every parameter change lands at the offset of its CC.
+pub struct MappedDevice {
+    device: Box<DevicePlugin>,
+    mappings: [Option<CcMapping>; NUM_CONTROLLERS],
+    learn_target: Option<i32>,
+    learning: bool,
+    events: Vec<TimedEvent>,
+}
+
+impl MappedDevice {
+    pub fn new (device: Box<DevicePlugin>) -> Self {
+        Self {
+            device: device,
+            mappings: [None; NUM_CONTROLLERS],
+            learn_target: None,
+            learning: false,
+            events: Vec::with_capacity(256),
+        }
+    }
+
+    /// Binds the next incoming CC to `param`.
+    pub fn learn (&mut self, param: i32) {
+        self.learn_target = Some(param);
+        self.learning = true;
+    }
+
+    pub fn cancel_learn (&mut self) {
+        self.learning = false;
+    }
+
+    pub fn map (&mut self, controller: u8, mapping: CcMapping) {
+        if (controller as usize) < NUM_CONTROLLERS {
+            self.mappings[controller as usize] = Some(mapping);
+        }
+    }
+
+    pub fn unmap (&mut self, controller: u8) {
+        if (controller as usize) < NUM_CONTROLLERS {
+            self.mappings[controller as usize] = None;
+        }
+    }
+
+    pub fn get_mapping (&self, controller: u8) -> Option<CcMapping> {
+        self.mappings.get(controller as usize).and_then(|mapping| *mapping)
+    }
+
+    fn learn_param (&self, index: i32) -> Option<LearnParams> {
+        let num_device_params = self.device.get_num_parameters();
+        if index >= num_device_params && ((index - num_device_params) as u32) < LearnParams::NUM_ITEMS {
+            Some(LearnParams::from_index((index - num_device_params) as u32))
+        } else {
+            None
+        }
+    }
+
+    fn target_mapping (&mut self) -> Option<&mut CcMapping> {
+        let target = self.learn_target;
+        self.mappings.iter_mut()
+            .filter_map(|mapping| mapping.as_mut())
+            .find(|mapping| Some(mapping.param) == target)
+    }
+
+    fn target_mapping_value (&self, param: LearnParams) -> f32 {
+        let target = self.learn_target;
+        let mapping = self.mappings.iter()
+            .filter_map(|mapping| mapping.as_ref())
+            .find(|mapping| Some(mapping.param) == target)
+            .cloned()
+            .unwrap_or(CcMapping::new(-1));
+        match param {
+            LearnParams::LearnTarget => {
+                let num = self.device.get_num_parameters() + 1;
+                let index = self.learn_target.map_or(0, |param| param + 1);
+                (index as f32 + 0.5) / num as f32
+            },
+            LearnParams::LearnMin => mapping.min,
+            LearnParams::LearnMax => mapping.max,
+            LearnParams::LearnInvert => if mapping.invert { 1.0 } else { 0.0 },
+        }
+    }
+
+    fn set_learn_parameter (&mut self, param: LearnParams, value: f32) {
+        match param {
+            LearnParams::LearnTarget => {
+                let num = self.device.get_num_parameters() + 1;
+                let index = ((value * num as f32) as i32).max(0).min(num - 1);
+                match index {
+                    0 => {
+                        self.learn_target = None;
+                        self.cancel_learn();
+                    },
+                    index => self.learn(index - 1),
+                }
+            },
+            LearnParams::LearnMin => {
+                self.target_mapping().map(|mapping| mapping.min = value);
+            },
+            LearnParams::LearnMax => {
+                self.target_mapping().map(|mapping| mapping.max = value);
+            },
+            LearnParams::LearnInvert => {
+                self.target_mapping().map(|mapping| mapping.invert = value > 0.5);
+            },
+        }
+    }
+
+    /// Returns true when the controller is mapped and was consumed.
+    fn map_cc (&mut self, controller: u8, value: u8) -> bool {
+        let index = controller as usize;
+        if index >= NUM_CONTROLLERS {
+            return false;
+        }
+
+        if self.learning {
+            if let Some(param) = self.learn_target {
+                // keep the ranges of a previous binding of the same parameter
+                let mut mapping = CcMapping::new(param);
+                for previous in self.mappings.iter_mut() {
+                    if previous.map_or(false, |previous| previous.param == param) {
+                        mapping = previous.take().unwrap();
+                    }
+                }
+                self.mappings[index] = Some(mapping);
+            }
+            self.learning = false;
+        }
+
+        match self.mappings[index] {
+            Some(mapping) => {
+                self.device.set_parameter(mapping.param, mapping.value(value));
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn map_message (&mut self, message: MidiMessage) -> bool {
+        match message {
+            MidiMessage::ControlChange { controller, value, .. } => self.map_cc(controller, value),
+            _ => false,
+        }
+    }
+
+    /// Whether `message` will be taken by `map_message`, without applying it.
+    fn is_mapped (&self, message: MidiMessage) -> bool {
+        match message {
+            MidiMessage::ControlChange { controller, .. } => {
+                let mapped = self.mappings.get(controller as usize).map_or(false, |mapping| mapping.is_some());
+                mapped || (self.learning && self.learn_target.is_some())
+            },
+            _ => false,
+        }
+    }
+
+    /// Runs the device from `start` to `end` with the events collected so far.
+    fn run_segment (&mut self, inputs: &mut Option<AudioBus<f32>>, outs: &mut AudioBus<f32>, start: usize, end: usize) {
+        let inputs = inputs.as_mut().map(|ins| {
+            let (left, right) = ins.split_at_mut(1);
+            [&mut left[0][start..end], &mut right[0][start..end]]
+        });
+        let (left, right) = outs.split_at_mut(1);
+        let segment: AudioBus<f32> = [&mut left[0][start..end], &mut right[0][start..end]];
+        self.device.run(&self.events, inputs, Some(segment));
+        self.events.clear();
+    }
+}
+
+impl Device for MappedDevice {
+    fn run<'a> (&mut self, events: &[TimedEvent], inputs: Option<AudioBus<'a, f32>>, outputs: Option<AudioBus<'a, f32>>) {
+        self.events.clear();
+        let mut outs = match outputs {
+            Some(outs) => outs,
+            None => {
+                for timed in events {
+                    if !self.map_message(timed.message) {
+                        self.events.push(*timed);
+                    }
+                }
+                self.device.run(&self.events, inputs, None);
+                return;
+            }
+        };
+
+        let mut inputs = inputs;
+        let len = cmp::min(outs[0].len(), outs[1].len());
+        let mut start = 0;
+        for timed in events {
+            let offset = cmp::min(timed.offset, len);
+            if self.is_mapped(timed.message) {
+                if offset > start {
+                    self.run_segment(&mut inputs, &mut outs, start, offset);
+                    start = offset;
+                }
+                if self.map_message(timed.message) {
+                    continue;
+                }
+            }
+            self.events.push(TimedEvent { offset: offset - start, message: timed.message });
+        }
+        self.run_segment(&mut inputs, &mut outs, start, len);
+    }
+
+    fn note_on (&mut self, note: u8, velocity: u8) {
+        self.device.note_on(note, velocity);
+    }
+
+    fn note_off (&mut self, note: u8, velocity: u8) {
+        self.device.note_off(note, velocity);
+    }
+
+    fn pitch_bend (&mut self, value: f32) {
+        self.device.pitch_bend(value);
+    }
+
+    fn poly_pressure (&mut self, note: u8, pressure: u8) {
+        self.device.poly_pressure(note, pressure);
+    }
+
+    fn channel_pressure (&mut self, pressure: u8) {
+        self.device.channel_pressure(pressure);
+    }
+
+    fn control_change (&mut self, controller: u8, value: u8) {
+        if !self.map_cc(controller, value) {
+            self.device.control_change(controller, value);
+        }
+    }
+
+    fn midi_message (&mut self, message: MidiMessage) {
+        if !self.map_message(message) {
+            self.device.midi_message(message);
+        }
+    }
+
+    fn set_sample_rate (&mut self, sample_rate: f32) {
+        self.device.set_sample_rate(sample_rate);
+    }
+
+    fn set_tempo (&mut self, bpm: f32) {
+        self.device.set_tempo(bpm);
+    }
+
+    fn get_parameter (&self, index: i32) -> f32 {
+        match self.learn_param(index) {
+            Some(param) => self.target_mapping_value(param),
+            None => self.device.get_parameter(index),
+        }
+    }
+
+    fn set_parameter (&mut self, index: i32, value: f32) {
+        match self.learn_param(index) {
+            Some(param) => self.set_learn_parameter(param, value),
+            None => self.device.set_parameter(index, value),
+        }
+    }
+
+    fn get_num_parameters (&self) -> i32 {
+        self.device.get_num_parameters() + LearnParams::NUM_ITEMS as i32
+    }
+}
+
+impl DevicePlugin for MappedDevice {
+    fn get_parameter_name (&self, index: i32) -> String {
+        match self.learn_param(index) {
+            Some(param) => format!("{:?}", param),
+            None => self.device.get_parameter_name(index),
+        }
+    }
+
+    fn get_parameter_label (&self, index: i32) -> String {
+        match self.learn_param(index) {
+            Some(_) => "".to_string(),
+            None => self.device.get_parameter_label(index),
+        }
+    }
+
+    fn can_be_automated (&self, index: i32) -> bool {
+        self.learn_param(index).is_none() && self.device.can_be_automated(index)
+    }
+
+    fn get_parameter_text (&self, index: i32) -> String {
+        match self.learn_param(index) {
+            Some(LearnParams::LearnTarget) => match self.learn_target {
+                Some(target) => {
+                    let name = self.device.get_parameter_name(target);
+                    if self.learning { format!("{} (learning)", name) } else { name }
+                },
+                None => "Off".to_string(),
+            },
+            Some(LearnParams::LearnInvert) => format!("{:?}", self.get_parameter(index) > 0.5),
+            Some(_) => format!("{:.3}", self.get_parameter(index)),
+            None => self.device.get_parameter_text(index),
+        }
+    }
+
+    fn set_wavetable (&mut self, osc: usize, table: Option<Arc<Wavetable>>) {
+        self.device.set_wavetable(osc, table);
+    }
+
+    /// The device's own state followed by the mappings, which refer to parameters by name.
+    fn get_state (&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        writer.write_header(STATE_VERSION);
+        writer.write_bytes(&self.device.get_state());
+        let count = self.mappings.iter().filter(|mapping| mapping.is_some()).count();
+        writer.write_u32(count as u32);
+        let mappings = self.mappings.iter().enumerate()
+            .filter_map(|(controller, mapping)| mapping.map(|mapping| (controller, mapping)));
+        for (controller, mapping) in mappings {
+            writer.write_u8(controller as u8);
+            writer.write_str(&self.device.get_parameter_name(mapping.param));
+            writer.write_f32(mapping.min);
+            writer.write_f32(mapping.max);
+            writer.write_u8(mapping.invert as u8);
+        }
+        writer.into_inner()
+    }
+
+    fn set_state (&mut self, data: &[u8]) {
+        let mut reader = StateReader::new(data);
+        if reader.read_header().is_none() {
+            return;
+        }
+        if let Some(device_state) = reader.read_bytes() {
+            self.device.set_state(device_state);
+        }
+
+        let indices = device::parameter_indices(&*self.device);
+        self.mappings = [None; NUM_CONTROLLERS];
+        let count = reader.read_u32().unwrap_or(0);
+        for _ in 0..count {
+            let controller = reader.read_u8();
+            let param = reader.read_str().map(|name| indices.get(name).cloned());
+            let min = reader.read_f32();
+            let max = reader.read_f32();
+            let invert = reader.read_u8();
+            match (controller, param, min, max, invert) {
+                (Some(controller), Some(param), Some(min), Some(max), Some(invert)) => {
+                    // parameters since removed from the device lose their mapping
+                    if let Some(param) = param {
+                        self.map(controller, CcMapping {
+                            param: param,
+                            min: min,
+                            max: max,
+                            invert: invert != 0,
+                        });
+                    }
+                },
+                _ => break,
+            }
+        }
+
+        // hosts may set every parameter around a restore, which must not leave learn mode armed
+        self.learn_target = None;
+        self.cancel_learn();
+    }
+}
+
+/// Device with two parameters, playing the value of the first as a constant.
+#[cfg(test)]
+#[derive(Default)]
+struct Probe {
+    params: [f32; 2],
+}
+
+#[cfg(test)]
+impl Device for Probe {
+    fn run<'a> (&mut self, _events: &[TimedEvent], _inputs: Option<AudioBus<'a, f32>>, outputs: Option<AudioBus<'a, f32>>) {
+        if let Some(outs) = outputs {
+            for sample in outs[0].iter_mut() {
+                *sample = self.params[0];
+            }
+        }
+    }
+
+    fn note_on (&mut self, _note: u8, _velocity: u8) {}
+    fn note_off (&mut self, _note: u8, _velocity: u8) {}
+    fn set_sample_rate (&mut self, _sample_rate: f32) {}
+    fn get_parameter (&self, index: i32) -> f32 { self.params[index as usize] }
+    fn set_parameter (&mut self, index: i32, value: f32) { self.params[index as usize] = value }
+    fn get_num_parameters (&self) -> i32 { 2 }
+}
+
+#[cfg(test)]
+impl DevicePlugin for Probe {
+    fn get_parameter_name (&self, index: i32) -> String { format!("Probe{}", index) }
+}
+
+#[cfg(test)]
+fn cc (offset: usize, controller: u8, value: u8) -> TimedEvent {
+    TimedEvent { offset: offset, message: MidiMessage::ControlChange { channel: 0, controller: controller, value: value } }
+}
+
+#[test]
+fn test_mapped_cc_lands_at_its_offset () {
+    let mut device = MappedDevice::new(Box::new(Probe::default()));
+    device.map(1, CcMapping::new(0));
+    let mut left = vec![0.0; 32];
+    let mut right = vec![0.0; 32];
+    device.run(&[cc(10, 1, 127), cc(20, 1, 0)], None, Some([&mut left, &mut right]));
+    assert!(left[..10].iter().all(|&sample| sample == 0.0));
+    assert!(left[10..20].iter().all(|&sample| sample == 1.0));
+    assert!(left[20..].iter().all(|&sample| sample == 0.0));
+}
+
+#[test]
+fn test_learn_target_is_kept_as_index () {
+    let mut device = MappedDevice::new(Box::new(Probe::default()));
+    let target = Probe::default().get_num_parameters() + LearnParams::LearnTarget.to_index() as i32;
+    assert!(!device.can_be_automated(target));
+    assert!(device.can_be_automated(0));
+    assert!(device.learn_param(device.get_num_parameters()).is_none());
+
+    // the second of three choices, after off
+    device.set_parameter(target, 0.5);
+    assert_eq!(device.get_parameter_text(target), "Probe0 (learning)");
+    device.set_parameter(target, device.get_parameter(target));
+    assert_eq!(device.learn_target, Some(0));
+    device.control_change(7, 64);
+    assert_eq!(device.get_mapping(7).map(|mapping| mapping.param), Some(0));
+}
+
+#[test]
+fn test_state_keeps_mappings_by_name () {
+    let mut device = MappedDevice::new(Box::new(Probe::default()));
+    device.set_parameter(1, 0.25);
+    device.map(7, CcMapping { param: 1, min: 0.5, max: 1.0, invert: true });
+    device.learn(0);
+    let state = device.get_state();
+
+    let mut restored = MappedDevice::new(Box::new(Probe::default()));
+    restored.set_state(&state);
+    assert_eq!(restored.get_parameter(1), 0.25);
+    let mapping = restored.get_mapping(7).unwrap();
+    assert_eq!((mapping.param, mapping.min, mapping.max, mapping.invert), (1, 0.5, 1.0, true));
+    assert!(!restored.learning);
+
+}
@@ -0,0 +1,168 @@
+use std::f32::consts::PI;
+use delay::read_fractional;
+use effect::Effect;
+use frame::Frame;
+use params_bag::ParamsBag;
+use IndexedEnum;
+
+const MIN_VOICES: usize = 2;
+const MAX_VOICES: usize = 4;
+const MIN_RATE: f32 = 0.05;
+// rates span a hundredfold range above MIN_RATE, up to 5Hz
+const RATE_RANGE: f32 = 100.0;
+const BASE_DELAY: f32 = 0.012;
+const MAX_DEPTH: f32 = 0.008;
+// with full spread the right channel's modulation runs a quarter cycle ahead
+const MAX_SPREAD_PHASE: f32 = 0.25;
+
+#[derive(Debug, Clone, Copy, IndexedEnum)]
+pub enum ChorusParams {
+    ChorusVoices,
+    ChorusRate,
+    ChorusDepth,
+    ChorusSpread,
+    ChorusMix,
+}
+
+define_params_bag!(ChorusParamsBag, ChorusParams, [
+    0.0, // voices, 2
+    0.3, // rate, about 0.2Hz
+    0.5, // depth, 4ms
+    1.0, // spread
+    0.0, // mix, off
+]);
+
+fn num_voices (value: f32) -> usize {
+    MIN_VOICES + (value * (MAX_VOICES - MIN_VOICES) as f32).round() as usize
+}
+
+fn rate (value: f32) -> f32 {
+    MIN_RATE * RATE_RANGE.powf(value)
+}
+
+/// Modulated delay chorus. Every voice reads the delay line at its own phase of a shared
+/// sine LFO, spread moves the right channel's phases away from the left ones.
+#[derive(Default)]
+pub struct Chorus {
+    params: ChorusParamsBag,
+    sample_rate: f32,
+    buffer_l: Vec<f32>,
+    buffer_r: Vec<f32>,
+    write: usize,
+    phase: f32,
+}
+
+impl Effect for Chorus {
+    fn get_num_parameters (&self) -> u32 {
+        ChorusParams::NUM_ITEMS
+    }
+
+    fn get_parameter (&self, index: u32) -> f32 {
+        self.params.get(ChorusParams::from_index(index))
+    }
+
+    fn set_parameter (&mut self, index: u32, value: f32) {
+        self.params.set(ChorusParams::from_index(index), value);
+    }
+
+    fn get_parameter_name (&self, index: u32) -> String {
+        format!("{:?}", ChorusParams::from_index(index))
+    }
+
+    fn get_parameter_label (&self, index: u32) -> String {
+        match ChorusParams::from_index(index) {
+            ChorusParams::ChorusRate => "Hz".to_string(),
+            ChorusParams::ChorusDepth => "ms".to_string(),
+            ChorusParams::ChorusSpread |
+            ChorusParams::ChorusMix => "%".to_string(),
+            _ => "".to_string(),
+        }
+    }
+
+    fn get_parameter_text (&self, index: u32) -> String {
+        let param = ChorusParams::from_index(index);
+        let value = self.params.get(param);
+        match param {
+            ChorusParams::ChorusVoices => format!("{}", num_voices(value)),
+            ChorusParams::ChorusRate => format!("{:.2}", rate(value)),
+            ChorusParams::ChorusDepth => format!("{:.1}", value * MAX_DEPTH * 1000.0),
+            ChorusParams::ChorusSpread |
+            ChorusParams::ChorusMix => format!("{:.0}", value * 100.0),
+        }
+    }
+
+    fn set_sample_rate (&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        let len = ((BASE_DELAY + MAX_DEPTH) * sample_rate) as usize + 2;
+        self.buffer_l = vec![0.0; len];
+        self.buffer_r = vec![0.0; len];
+        self.write = 0;
+    }
+
+    fn is_active (&self) -> bool {
+        self.params.get(ChorusParams::ChorusMix) > 0.0
+    }
+
+    fn tail_gap (&self) -> usize {
+        self.buffer_l.len()
+    }
+
+    fn reset (&mut self) {
+        for sample in self.buffer_l.iter_mut().chain(self.buffer_r.iter_mut()) {
+            *sample = 0.0;
+        }
+    }
+
+    #[inline]
+    fn process (&mut self, frame: Frame) -> Frame {
+        if self.buffer_l.is_empty() {
+            return frame;
+        }
+
+        self.buffer_l[self.write] = frame.l;
+        self.buffer_r[self.write] = frame.r;
+
+        let voices = num_voices(self.params.get(ChorusParams::ChorusVoices));
+        let depth = self.params.get(ChorusParams::ChorusDepth) * MAX_DEPTH * self.sample_rate;
+        let base = BASE_DELAY * self.sample_rate;
+        let spread = self.params.get(ChorusParams::ChorusSpread) * MAX_SPREAD_PHASE;
+        let mut wet_l = 0.0;
+        let mut wet_r = 0.0;
+        for voice in 0..voices {
+            let phase = self.phase + voice as f32 / voices as f32;
+            let delay_l = base + depth * (2.0 * PI * phase).sin();
+            let delay_r = base + depth * (2.0 * PI * (phase + spread)).sin();
+            wet_l += read_fractional(&self.buffer_l, self.write, delay_l);
+            wet_r += read_fractional(&self.buffer_r, self.write, delay_r);
+        }
+        let gain = 1.0 / voices as f32;
+
+        self.write = (self.write + 1) % self.buffer_l.len();
+        self.phase += rate(self.params.get(ChorusParams::ChorusRate)) / self.sample_rate;
+        self.phase -= self.phase.floor();
+
+        let mix = self.params.get(ChorusParams::ChorusMix);
+        Frame {
+            l: frame.l * (1.0 - mix) + wet_l * gain * mix,
+            r: frame.r * (1.0 - mix) + wet_r * gain * mix,
+        }
+    }
+}
+
+#[test]
+fn test_chorus_passes_dc () {
+    let mut chorus = Chorus::default();
+    chorus.set_sample_rate(44100.0);
+    chorus.set_parameter(ChorusParams::ChorusVoices.to_index(), 1.0);
+    chorus.set_parameter(ChorusParams::ChorusDepth.to_index(), 1.0);
+    chorus.set_parameter(ChorusParams::ChorusMix.to_index(), 1.0);
+
+    // once the delay line is full the voices read the same constant input
+    for i in 0..4410 {
+        let out = chorus.process(Frame { l: 0.5, r: -0.5 });
+        if i > 1000 {
+            assert!((out.l - 0.5).abs() < 0.0001);
+            assert!((out.r + 0.5).abs() < 0.0001);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::f32::consts::PI;
+use effect::Effect;
+use filter;
+use frame::Frame;
+use lfo::{LfoDivision, DEFAULT_TEMPO};
+use params_bag::ParamsBag;
+use smoother::Smoother;
+use IndexedEnum;
+
+// longest delay the buffers hold, synced times beyond it are clamped
+const MAX_DELAY_TIME: f32 = 5.0;
+const MIN_DELAY_TIME: f32 = 0.001;
+const FREE_DELAY_TIME: f32 = 2.0;
+const MAX_FEEDBACK: f32 = 0.98;
+// delay time changes glide instead of jumping, like a tape delay
+const TIME_SMOOTHING: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy, IndexedEnum)]
+pub enum DelayParams {
+    DelayTime,
+    DelaySync,
+    DelayFeedback,
+    DelayPingPong,
+    DelayTone,
+    DelayMix,
+}
+
+define_params_bag!(DelayParamsBag, DelayParams, [
+    0.4, // time, 320ms
+    0.0, // sync
+    0.4, // feedback
+    0.0, // ping-pong
+    0.8, // tone, about 5kHz
+    0.0, // mix, off
+]);
+
+fn delay_time (value: f32) -> f32 {
+    (value * value * FREE_DELAY_TIME).max(MIN_DELAY_TIME)
+}
+
+/// Reads a circular buffer `delay` samples behind the `write` position, interpolating linearly.
+#[inline]
+pub fn read_fractional (buffer: &[f32], write: usize, delay: f32) -> f32 {
+    let len = buffer.len();
+    let position = write as f32 + len as f32 - delay;
+    let index = position.floor() as usize;
+    let frac = position - position.floor();
+    let a = buffer[index % len];
+    let b = buffer[(index + 1) % len];
+    a + (b - a) * frac
+}
+
+/// Stereo feedback delay with a lowpass in the feedback path.
+/// In ping-pong mode the input enters on the left and every repeat changes side.
+pub struct Delay {
+    params: DelayParamsBag,
+    sample_rate: f32,
+    tempo: f32,
+    buffer_l: Vec<f32>,
+    buffer_r: Vec<f32>,
+    write: usize,
+    delay: Smoother,
+    feedback: f32,
+    damping: f32,
+    tone: [f32; 2],
+}
+
+impl Default for Delay {
+    fn default () -> Delay {
+        Delay {
+            params: Default::default(),
+            sample_rate: 0.0,
+            tempo: DEFAULT_TEMPO,
+            buffer_l: Vec::new(),
+            buffer_r: Vec::new(),
+            write: 0,
+            delay: Default::default(),
+            feedback: 0.0,
+            damping: 1.0,
+            tone: [0.0; 2],
+        }
+    }
+}
+
+impl Delay {
+    fn synced (&self) -> bool {
+        self.params.get(DelayParams::DelaySync) > 0.5
+    }
+
+    /// Delay time in seconds, either free or a note length at the host tempo.
+    pub fn time (&self) -> f32 {
+        let value = self.params.get(DelayParams::DelayTime);
+        let time = if self.synced() {
+            LfoDivision::from_param(value).beats() * 60.0 / self.tempo
+        } else {
+            delay_time(value)
+        };
+        time.min(MAX_DELAY_TIME)
+    }
+
+    fn update (&mut self) {
+        let samples = (self.time() * self.sample_rate).min(self.buffer_l.len() as f32 - 1.0);
+        self.delay.set_target(samples.max(1.0));
+        self.feedback = self.params.get(DelayParams::DelayFeedback) * MAX_FEEDBACK;
+        let cutoff = filter::control_to_cutoff(self.params.get(DelayParams::DelayTone));
+        self.damping = 1.0 - (-2.0 * PI * cutoff / self.sample_rate).exp();
+    }
+}
+
+impl Effect for Delay {
+    fn get_num_parameters (&self) -> u32 {
+        DelayParams::NUM_ITEMS
+    }
+
+    fn get_parameter (&self, index: u32) -> f32 {
+        self.params.get(DelayParams::from_index(index))
+    }
+
+    fn set_parameter (&mut self, index: u32, value: f32) {
+        self.params.set(DelayParams::from_index(index), value);
+        self.update();
+    }
+
+    fn get_parameter_name (&self, index: u32) -> String {
+        format!("{:?}", DelayParams::from_index(index))
+    }
+
+    fn get_parameter_label (&self, index: u32) -> String {
+        match DelayParams::from_index(index) {
+            DelayParams::DelayTime if !self.synced() => "ms".to_string(),
+            DelayParams::DelayTone => "Hz".to_string(),
+            DelayParams::DelayFeedback |
+            DelayParams::DelayMix => "%".to_string(),
+            _ => "".to_string(),
+        }
+    }
+
+    fn get_parameter_text (&self, index: u32) -> String {
+        let param = DelayParams::from_index(index);
+        let value = self.params.get(param);
+        match param {
+            DelayParams::DelayTime if self.synced() => format!("{:?}", LfoDivision::from_param(value)),
+            DelayParams::DelayTime => format!("{:.0}", delay_time(value) * 1000.0),
+            DelayParams::DelaySync |
+            DelayParams::DelayPingPong => format!("{:?}", value > 0.5),
+            DelayParams::DelayTone => format!("{:.0}", filter::control_to_cutoff(value)),
+            DelayParams::DelayFeedback |
+            DelayParams::DelayMix => format!("{:.0}", value * 100.0),
+        }
+    }
+
+    fn set_sample_rate (&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        let len = (MAX_DELAY_TIME * sample_rate) as usize + 2;
+        self.buffer_l = vec![0.0; len];
+        self.buffer_r = vec![0.0; len];
+        self.write = 0;
+        self.delay.set_time(sample_rate, TIME_SMOOTHING);
+        self.update();
+        self.delay.jump();
+    }
+
+    fn set_tempo (&mut self, bpm: f32) {
+        if bpm > 0.0 && bpm != self.tempo {
+            self.tempo = bpm;
+            self.update();
+        }
+    }
+
+    fn is_active (&self) -> bool {
+        self.params.get(DelayParams::DelayMix) > 0.0
+    }
+
+    fn tail_gap (&self) -> usize {
+        self.delay.get_value() as usize + 1
+    }
+
+    fn reset (&mut self) {
+        for sample in self.buffer_l.iter_mut().chain(self.buffer_r.iter_mut()) {
+            *sample = 0.0;
+        }
+        self.tone = [0.0; 2];
+    }
+
+    #[inline]
+    fn process (&mut self, frame: Frame) -> Frame {
+        if self.buffer_l.is_empty() {
+            return frame;
+        }
+
+        let delay = self.delay.process();
+        let delayed_l = read_fractional(&self.buffer_l, self.write, delay);
+        let delayed_r = read_fractional(&self.buffer_r, self.write, delay);
+        self.tone[0] += (delayed_l - self.tone[0]) * self.damping;
+        self.tone[1] += (delayed_r - self.tone[1]) * self.damping;
+
+        let (input_l, input_r) = if self.params.get(DelayParams::DelayPingPong) > 0.5 {
+            ((frame.l + frame.r) * 0.5 + self.tone[1] * self.feedback, self.tone[0] * self.feedback)
+        } else {
+            (frame.l + self.tone[0] * self.feedback, frame.r + self.tone[1] * self.feedback)
+        };
+        self.buffer_l[self.write] = input_l;
+        self.buffer_r[self.write] = input_r;
+        self.write = (self.write + 1) % self.buffer_l.len();
+
+        let mix = self.params.get(DelayParams::DelayMix);
+        Frame {
+            l: frame.l * (1.0 - mix) + delayed_l * mix,
+            r: frame.r * (1.0 - mix) + delayed_r * mix,
+        }
+    }
+}
+
+#[test]
+fn test_ping_pong_repeats_alternate () {
+    let mut delay = Delay::default();
+    delay.set_sample_rate(1000.0);
+    delay.set_parameter(DelayParams::DelayTime.to_index(), 0.5);
+    delay.set_parameter(DelayParams::DelayPingPong.to_index(), 1.0);
+    delay.set_parameter(DelayParams::DelayFeedback.to_index(), 1.0);
+    delay.set_parameter(DelayParams::DelayTone.to_index(), 1.0);
+    delay.set_parameter(DelayParams::DelayMix.to_index(), 1.0);
+
+    // 0.5 maps to 500ms, 500 samples
+    let out: Vec<Frame> = (0..1600)
+        .map(|i| delay.process(Frame { l: if i == 0 { 1.0 } else { 0.0 }, r: 0.0 }))
+        .collect();
+    assert!(out[500].l > 0.4 && out[500].r == 0.0);
+    assert!(out[1000].r > 0.4 && out[1000].l == 0.0);
+    assert!(out[1500].l > 0.4 && out[1500].r == 0.0);
+}
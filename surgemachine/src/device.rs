@@ -1,18 +1,96 @@
+use midi::MidiMessage;
+use state::{StateReader, StateWriter};
+use std::collections::HashMap;
+use std::sync::Arc;
+use wavetable::Wavetable;
 
 pub type AudioBus<'a, T> = [&'a mut [T]; 2];
 
+/// Message scheduled at `offset` samples from the start of the processed block.
+#[derive(Debug, Clone, Copy)]
+pub struct TimedEvent {
+    pub offset: usize,
+    pub message: MidiMessage,
+}
+
 pub trait Device {
-    fn run<'a> (&mut self, inputs: Option<AudioBus<'a, f32>>, outputs: Option<AudioBus<'a, f32>>);
+    /// `events` are expected to be sorted by offset.
+    fn run<'a> (&mut self, events: &[TimedEvent], inputs: Option<AudioBus<'a, f32>>, outputs: Option<AudioBus<'a, f32>>);
 
     fn note_on (&mut self, note: u8, velocity: u8);
     fn note_off (&mut self, note: u8, velocity: u8);
+    /// Bend amount in range -1.0 to 1.0, scaled by the device's bend range.
+    fn pitch_bend (&mut self, _value: f32) {}
+    fn control_change (&mut self, _controller: u8, _value: u8) {}
+    fn poly_pressure (&mut self, _note: u8, _pressure: u8) {}
+    fn channel_pressure (&mut self, _pressure: u8) {}
+
+    fn midi_message (&mut self, message: MidiMessage) {
+        dispatch_midi(self, message);
+    }
 
     fn set_sample_rate(&mut self, sample_rate: f32);
+    /// Host tempo in beats per minute, for tempo synced modulation.
+    fn set_tempo(&mut self, _bpm: f32) {}
     fn get_parameter(&self, index: i32) -> f32;
     fn set_parameter(&mut self, index: i32, val: f32);
     fn get_num_parameters(&self) -> i32;
 }
 
+/// Routes a message to the matching `Device` entry point regardless of its channel.
+pub fn dispatch_midi<D: Device + ?Sized> (device: &mut D, message: MidiMessage) {
+    match message {
+        MidiMessage::NoteOn { note, velocity, .. } => device.note_on(note, velocity),
+        MidiMessage::NoteOff { note, velocity, .. } => device.note_off(note, velocity),
+        MidiMessage::PitchBend { value, .. } => device.pitch_bend(value as f32 / 8192.0),
+        MidiMessage::ControlChange { controller, value, .. } => device.control_change(controller, value),
+        MidiMessage::PolyPressure { note, pressure, .. } => device.poly_pressure(note, pressure),
+        MidiMessage::ChannelPressure { pressure, .. } => device.channel_pressure(pressure),
+        _ => ()
+    }
+}
+
+// version of the parameter chunks written by `write_parameters`
+const PARAMETERS_VERSION: u32 = 1;
+
+/// Writes every parameter value keyed by its name.
+pub fn write_parameters<D: DevicePlugin + ?Sized> (device: &D, writer: &mut StateWriter) {
+    writer.write_header(PARAMETERS_VERSION);
+    writer.write_u32(device.get_num_parameters() as u32);
+    for index in 0..device.get_num_parameters() {
+        writer.write_str(&device.get_parameter_name(index));
+        writer.write_f32(device.get_parameter(index));
+    }
+}
+
+/// Reads values written by `write_parameters`. Parameters missing from the data keep their values
+/// and unknown names are skipped, so parameters can be added or moved between versions.
+pub fn read_parameters<D: DevicePlugin + ?Sized> (device: &mut D, reader: &mut StateReader) {
+    if reader.read_header().is_none() {
+        return;
+    }
+
+    let indices = parameter_indices(device);
+    let count = reader.read_u32().unwrap_or(0);
+    for _ in 0..count {
+        match (reader.read_str(), reader.read_f32()) {
+            (Some(name), Some(value)) => {
+                if let Some(&index) = indices.get(name) {
+                    device.set_parameter(index, value);
+                }
+            },
+            _ => break,
+        }
+    }
+}
+
+/// Index of every parameter by its name.
+pub fn parameter_indices<D: DevicePlugin + ?Sized> (device: &D) -> HashMap<String, i32> {
+    (0..device.get_num_parameters())
+        .map(|index| (device.get_parameter_name(index), index))
+        .collect()
+}
+
 // plugin specific
 pub trait DevicePlugin: Device {
     fn get_parameter_name(&self, index: i32) -> String { format!("{}", index) }
@@ -21,4 +99,21 @@ pub trait DevicePlugin: Device {
         let value = self.get_parameter(index);
         format!("{:.3}", value)
     }
+    /// Parameters changing how the device is controlled rather than how it sounds
+    /// are kept away from host automation.
+    fn can_be_automated(&self, _index: i32) -> bool { true }
+
+    /// Serializes the parameter values by name, parameters added later keep their defaults on load.
+    fn get_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        write_parameters(self, &mut writer);
+        writer.into_inner()
+    }
+
+    fn set_state(&mut self, data: &[u8]) {
+        read_parameters(self, &mut StateReader::new(data));
+    }
+
+    /// Assigns a wavetable to one of the device's oscillators, devices without tables ignore it.
+    fn set_wavetable(&mut self, _osc: usize, _table: Option<Arc<Wavetable>>) {}
 }
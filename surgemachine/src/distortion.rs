@@ -0,0 +1,397 @@
+use effect::Effect;
+use filter::{Filter, FilterMode};
+use frame::Frame;
+use params_bag::ParamsBag;
+use IndexedEnum;
+
+const MAX_DRIVE_DB: f32 = 36.0;
+const MAX_BITS: f32 = 16.0;
+const MAX_DOWNSAMPLE: f32 = 32.0;
+// anti-imaging and anti-aliasing cutoff, relative to the base sample rate
+const OVERSAMPLING_CUTOFF: f32 = 0.45;
+// resonances making each pair of stages a fourth order Butterworth lowpass, flat below the cutoff
+const BUTTERWORTH_RESONANCE: [f32; 2] = [0.0769, 0.6235];
+
+#[derive(Debug, Clone, Copy, PartialEq, IndexedEnum)]
+pub enum DistortionCurve {
+    Tanh,
+    HardClip,
+    Foldback,
+    Chebyshev,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, IndexedEnum)]
+pub enum Oversampling {
+    Off,
+    Twice,
+    FourTimes,
+}
+
+impl Oversampling {
+    pub fn factor (&self) -> usize {
+        match *self {
+            Oversampling::Off => 1,
+            Oversampling::Twice => 2,
+            Oversampling::FourTimes => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, IndexedEnum)]
+pub enum DistortionParams {
+    DistortionCurve,
+    DistortionDrive,
+    DistortionOversampling,
+    DistortionBits,
+    DistortionDownsample,
+    DistortionMix,
+}
+
+define_params_bag!(DistortionParamsBag, DistortionParams, [
+    0.0, // curve, tanh
+    0.3, // drive, about 11dB
+    0.0, // oversampling, off
+    1.0, // bits, no reduction
+    0.0, // downsample, no reduction
+    0.0, // mix, off
+]);
+
+fn drive_db (value: f32) -> f32 {
+    value * MAX_DRIVE_DB
+}
+
+fn bits (value: f32) -> f32 {
+    (1.0 + value * (MAX_BITS - 1.0)).round()
+}
+
+fn downsample (value: f32) -> f32 {
+    1.0 + value * (MAX_DOWNSAMPLE - 1.0)
+}
+
+impl DistortionCurve {
+    #[inline]
+    pub fn shape (&self, x: f32) -> f32 {
+        match *self {
+            DistortionCurve::Tanh => x.tanh(),
+            DistortionCurve::HardClip => x.max(-1.0).min(1.0),
+            DistortionCurve::Foldback => {
+                // triangle through the origin, folding back at every odd integer
+                let t = (x + 1.0) * 0.25;
+                1.0 - 4.0 * (t - t.floor() - 0.5).abs()
+            },
+            DistortionCurve::Chebyshev => {
+                // third order polynomial, turns a full scale sine into its third harmonic
+                let x = x.max(-1.0).min(1.0);
+                4.0 * x * x * x - 3.0 * x
+            },
+        }
+    }
+}
+
+/// Waveshaper followed by a bitcrusher. The shaper can run oversampled, with
+/// the input zero-stuffed and filtered on the way up and filtered again on the way down.
+/// The dry signal takes the same filters, so the two stay aligned when mixed.
+pub struct Distortion {
+    params: DistortionParamsBag,
+    sample_rate: f32,
+    curve: DistortionCurve,
+    gain: f32,
+    oversampling: Oversampling,
+    upsample: [Filter; 2],
+    downsample: [Filter; 2],
+    dry_downsample: [Filter; 2],
+    levels: Option<f32>,
+    hold_step: f32,
+    hold_phase: f32,
+    held: Frame,
+}
+
+impl Default for Distortion {
+    fn default () -> Distortion {
+        let mut distortion = Distortion {
+            params: Default::default(),
+            sample_rate: 0.0,
+            curve: DistortionCurve::Tanh,
+            gain: 1.0,
+            oversampling: Oversampling::Off,
+            upsample: Default::default(),
+            downsample: Default::default(),
+            dry_downsample: Default::default(),
+            levels: None,
+            hold_step: 1.0,
+            hold_phase: 0.0,
+            held: Frame::default(),
+        };
+        distortion.update();
+        distortion
+    }
+}
+
+impl Distortion {
+    fn update (&mut self) {
+        self.curve = DistortionCurve::from_param(self.params.get(DistortionParams::DistortionCurve));
+        self.gain = 10f32.powf(drive_db(self.params.get(DistortionParams::DistortionDrive)) / 20.0);
+
+        let bits = bits(self.params.get(DistortionParams::DistortionBits));
+        self.levels = if bits < MAX_BITS { Some((bits - 1.0).exp2()) } else { None };
+        self.hold_step = 1.0 / downsample(self.params.get(DistortionParams::DistortionDownsample));
+
+        let oversampling = Oversampling::from_param(self.params.get(DistortionParams::DistortionOversampling));
+        if oversampling != self.oversampling {
+            self.oversampling = oversampling;
+            self.update_filters();
+        }
+    }
+
+    fn update_filters (&mut self) {
+        if self.sample_rate <= 0.0 {
+            return;
+        }
+        let rate = self.sample_rate * self.oversampling.factor() as f32;
+        let cutoff = self.sample_rate * OVERSAMPLING_CUTOFF;
+        for (i, filter) in self.filters_mut().enumerate() {
+            filter.set_sample_rate(rate);
+            filter.set_mode(FilterMode::LowPass);
+            filter.set_resonance(BUTTERWORTH_RESONANCE[i % 2]);
+            filter.set_cutoff(cutoff);
+            filter.reset();
+        }
+    }
+
+    fn filters_mut (&mut self) -> impl Iterator<Item=&mut Filter> {
+        self.upsample.iter_mut()
+            .chain(self.downsample.iter_mut())
+            .chain(self.dry_downsample.iter_mut())
+    }
+
+    #[inline]
+    fn shape (&self, frame: Frame) -> Frame {
+        Frame {
+            l: self.curve.shape(frame.l * self.gain),
+            r: self.curve.shape(frame.r * self.gain),
+        }
+    }
+
+    /// Returns the dry and the shaped signal, both delayed by the oversampling filters.
+    #[inline]
+    fn shape_oversampled (&mut self, frame: Frame) -> (Frame, Frame) {
+        let factor = self.oversampling.factor();
+        if factor == 1 {
+            let shaped = self.shape(Frame { l: frame.l, r: frame.r });
+            return (frame, shaped);
+        }
+
+        let mut dry = Frame::default();
+        let mut wet = Frame::default();
+        for i in 0..factor {
+            // zero-stuffing loses 1/factor of the level, made up on the first sample
+            let input = if i == 0 { Frame { l: frame.l, r: frame.r } * factor as f32 } else { Frame::default() };
+            let input = self.upsample[0].process(input);
+            let input = self.upsample[1].process(input);
+            let shaped = self.shape(Frame { l: input.l, r: input.r });
+            let shaped = self.downsample[0].process(shaped);
+            wet = self.downsample[1].process(shaped);
+            let input = self.dry_downsample[0].process(input);
+            dry = self.dry_downsample[1].process(input);
+        }
+        (dry, wet)
+    }
+
+    #[inline]
+    fn crush (&mut self, frame: Frame) -> Frame {
+        self.hold_phase += self.hold_step;
+        if self.hold_phase >= 1.0 {
+            self.hold_phase -= 1.0;
+            self.held = match self.levels {
+                Some(levels) => Frame {
+                    l: (frame.l * levels).round() / levels,
+                    r: (frame.r * levels).round() / levels,
+                },
+                None => frame,
+            };
+        }
+        Frame { l: self.held.l, r: self.held.r }
+    }
+}
+
+impl Effect for Distortion {
+    fn get_num_parameters (&self) -> u32 {
+        DistortionParams::NUM_ITEMS
+    }
+
+    fn get_parameter (&self, index: u32) -> f32 {
+        self.params.get(DistortionParams::from_index(index))
+    }
+
+    fn set_parameter (&mut self, index: u32, value: f32) {
+        self.params.set(DistortionParams::from_index(index), value);
+        self.update();
+    }
+
+    fn get_parameter_name (&self, index: u32) -> String {
+        format!("{:?}", DistortionParams::from_index(index))
+    }
+
+    fn get_parameter_label (&self, index: u32) -> String {
+        match DistortionParams::from_index(index) {
+            DistortionParams::DistortionDrive => "dB".to_string(),
+            DistortionParams::DistortionMix => "%".to_string(),
+            _ => "".to_string(),
+        }
+    }
+
+    fn get_parameter_text (&self, index: u32) -> String {
+        let param = DistortionParams::from_index(index);
+        let value = self.params.get(param);
+        match param {
+            DistortionParams::DistortionCurve => format!("{:?}", DistortionCurve::from_param(value)),
+            DistortionParams::DistortionDrive => format!("{:.1}", drive_db(value)),
+            DistortionParams::DistortionOversampling => format!("{}x", Oversampling::from_param(value).factor()),
+            DistortionParams::DistortionBits => format!("{}", bits(value)),
+            DistortionParams::DistortionDownsample => format!("{:.1}", downsample(value)),
+            DistortionParams::DistortionMix => format!("{:.0}", value * 100.0),
+        }
+    }
+
+    fn set_sample_rate (&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.update();
+        self.update_filters();
+    }
+
+    fn is_active (&self) -> bool {
+        self.params.get(DistortionParams::DistortionMix) > 0.0
+    }
+
+    fn tail_gap (&self) -> usize {
+        // a held sample can be the only sound for a whole hold period
+        (1.0 / self.hold_step).ceil() as usize
+    }
+
+    fn reset (&mut self) {
+        for filter in self.filters_mut() {
+            filter.reset();
+        }
+        self.hold_phase = 0.0;
+        self.held = Frame::default();
+    }
+
+    #[inline]
+    fn process (&mut self, frame: Frame) -> Frame {
+        let (dry, wet) = self.shape_oversampled(frame);
+        let wet = self.crush(wet);
+        let mix = self.params.get(DistortionParams::DistortionMix);
+        Frame {
+            l: dry.l * (1.0 - mix) + wet.l * mix,
+            r: dry.r * (1.0 - mix) + wet.r * mix,
+        }
+    }
+}
+
+#[test]
+fn test_curves_stay_bounded () {
+    let curves = [DistortionCurve::Tanh, DistortionCurve::HardClip, DistortionCurve::Foldback, DistortionCurve::Chebyshev];
+    for curve in curves.iter() {
+        assert!(curve.shape(0.0).abs() < 0.0001);
+        for i in -100..100 {
+            assert!(curve.shape(i as f32 * 0.1).abs() <= 1.0001);
+        }
+    }
+    assert!((DistortionCurve::Foldback.shape(1.5) - 0.5).abs() < 0.0001);
+    assert_eq!(DistortionCurve::Chebyshev.shape(1.0), 1.0);
+}
+
+#[cfg(test)]
+fn sine (freq: f32, sample_rate: f32, amplitude: f32, len: usize) -> Vec<f32> {
+    (0..len).map(|i| amplitude * (2.0 * ::std::f32::consts::PI * freq * i as f32 / sample_rate).sin()).collect()
+}
+
+#[cfg(test)]
+fn distort (params: &[(DistortionParams, f32)], input: &[f32]) -> Vec<f32> {
+    let mut distortion = Distortion::default();
+    distortion.set_sample_rate(44100.0);
+    for &(param, value) in params {
+        distortion.set_parameter(param.to_index(), value);
+    }
+    input.iter().map(|&x| distortion.process(Frame { l: x, r: x }).l).collect()
+}
+
+#[test]
+fn test_bit_reduction_quantizes () {
+    // 4 bits leave 8 steps per unit, the clipper passes the ramp through untouched
+    let input: Vec<f32> = (0..100).map(|i| i as f32 / 100.0 - 0.5).collect();
+    let output = distort(&[
+        (DistortionParams::DistortionCurve, 0.3),
+        (DistortionParams::DistortionDrive, 0.0),
+        (DistortionParams::DistortionBits, 3.0 / 15.0),
+        (DistortionParams::DistortionMix, 1.0),
+    ], &input);
+    for (x, y) in input.iter().zip(output.iter()) {
+        assert_eq!(*y, (x * 8.0).round() / 8.0);
+    }
+}
+
+#[test]
+fn test_downsample_holds_samples () {
+    // a factor of 4 keeps every fourth input for four samples
+    let input: Vec<f32> = (0..64).map(|i| i as f32 / 64.0 - 0.5).collect();
+    let output = distort(&[
+        (DistortionParams::DistortionCurve, 0.3),
+        (DistortionParams::DistortionDrive, 0.0),
+        (DistortionParams::DistortionDownsample, 3.0 / 31.0),
+        (DistortionParams::DistortionMix, 1.0),
+    ], &input);
+    assert_eq!(&output[..3], &[0.0; 3]);
+    for i in 3..64 {
+        assert_eq!(output[i], input[(i + 1) / 4 * 4 - 1]);
+    }
+}
+
+#[cfg(test)]
+fn rms (signal: &[f32]) -> f32 {
+    (signal.iter().map(|x| x * x).sum::<f32>() / signal.len() as f32).sqrt()
+}
+
+#[cfg(test)]
+fn magnitude_at (signal: &[f32], freq: f32, sample_rate: f32) -> f32 {
+    let (re, im) = signal.iter().enumerate().fold((0.0, 0.0), |(re, im), (i, x)| {
+        let phase = 2.0 * ::std::f32::consts::PI * freq * i as f32 / sample_rate;
+        (re + x * phase.cos(), im + x * phase.sin())
+    });
+    (re * re + im * im).sqrt() / signal.len() as f32
+}
+
+#[test]
+fn test_oversampling_keeps_level () {
+    // the clipper leaves a quiet sine alone, dry and wet alike go through the filters
+    let input = sine(5000.0, 44100.0, 0.5, 4410);
+    for &oversampling in [0.5, 1.0].iter() {
+        for &mix in [0.5, 1.0].iter() {
+            let output = distort(&[
+                (DistortionParams::DistortionCurve, 0.3),
+                (DistortionParams::DistortionDrive, 0.0),
+                (DistortionParams::DistortionOversampling, oversampling),
+                (DistortionParams::DistortionMix, mix),
+            ], &input);
+            assert!((rms(&output[441..]) / rms(&input[441..]) - 1.0).abs() < 0.01);
+        }
+    }
+}
+
+#[test]
+fn test_oversampling_reduces_aliasing () {
+    // the seventh harmonic of 5kHz folds back to 9.1kHz without oversampling
+    let input = sine(5000.0, 44100.0, 0.5, 44100);
+    let alias = |oversampling: f32| {
+        let output = distort(&[
+            (DistortionParams::DistortionCurve, 0.3),
+            (DistortionParams::DistortionDrive, 1.0),
+            (DistortionParams::DistortionOversampling, oversampling),
+            (DistortionParams::DistortionMix, 1.0),
+        ], &input);
+        magnitude_at(&output[4410..], 9100.0, 44100.0)
+    };
+    let off = alias(0.0);
+    assert!(alias(0.5) < off * 0.25);
+    assert!(alias(1.0) < off * 0.25);
+}
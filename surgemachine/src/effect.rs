@@ -0,0 +1,272 @@
+use device::{AudioBus, Device, DevicePlugin, TimedEvent};
+use frame::Frame;
+use delay::Delay;
+use reverb::Reverb;
+use chorus::Chorus;
+use distortion::Distortion;
+use std::cmp;
+
+// about -100dB, below it a unit's input and output count as silence
+const SILENCE_THRESHOLD: f32 = 0.00001;
+
+/// Stereo processor placed after the voices. Parameters are indexed from zero
+/// within the unit, the chain offsets them past those of the device.
+pub trait Effect {
+    fn get_num_parameters (&self) -> u32;
+    fn get_parameter (&self, index: u32) -> f32;
+    fn set_parameter (&mut self, index: u32, value: f32);
+    fn get_parameter_name (&self, index: u32) -> String;
+    fn get_parameter_label (&self, _index: u32) -> String { "".to_string() }
+    fn get_parameter_text (&self, index: u32) -> String {
+        format!("{:.3}", self.get_parameter(index))
+    }
+
+    /// Buffers are sized here, never while processing.
+    fn set_sample_rate (&mut self, sample_rate: f32);
+    fn set_tempo (&mut self, _bpm: f32) {}
+    /// Inactive units are skipped by the chain, their state is cleared when enabled again.
+    fn is_active (&self) -> bool { true }
+    /// Longest run of silent output samples after which more output may still follow,
+    /// like the wait for the first repeat of a delay.
+    fn tail_gap (&self) -> usize { 0 }
+    fn reset (&mut self) {}
+    fn process (&mut self, frame: Frame) -> Frame;
+}
+
+/// Effect units processed in order, each one fed by the output of the previous.
+#[derive(Default)]
+pub struct EffectChain {
+    effects: Vec<Box<Effect>>,
+    active: Vec<bool>,
+    // samples each unit has spent with silent input and output
+    silent: Vec<usize>,
+}
+
+impl EffectChain {
+    pub fn with (mut self, effect: Box<Effect>) -> Self {
+        self.active.push(effect.is_active());
+        self.silent.push(usize::max_value());
+        self.effects.push(effect);
+        self
+    }
+
+    pub fn get_num_parameters (&self) -> u32 {
+        self.effects.iter().map(|effect| effect.get_num_parameters()).sum()
+    }
+
+    /// Unit owning the chain parameter `index` and the parameter's index within it.
+    fn locate (&self, index: u32) -> Option<(usize, u32)> {
+        let mut first = 0;
+        for (unit, effect) in self.effects.iter().enumerate() {
+            let num = effect.get_num_parameters();
+            if index < first + num {
+                return Some((unit, index - first));
+            }
+            first += num;
+        }
+        None
+    }
+
+    pub fn get_parameter (&self, index: u32) -> f32 {
+        self.locate(index).map_or(0.0, |(unit, index)| self.effects[unit].get_parameter(index))
+    }
+
+    pub fn set_parameter (&mut self, index: u32, value: f32) {
+        if let Some((unit, index)) = self.locate(index) {
+            let effect = &mut self.effects[unit];
+            effect.set_parameter(index, value);
+            let active = effect.is_active();
+            if active && !self.active[unit] {
+                effect.reset();
+                self.silent[unit] = usize::max_value();
+            }
+            self.active[unit] = active;
+        }
+    }
+
+    pub fn get_parameter_name (&self, index: u32) -> String {
+        self.locate(index).map_or(format!("{}", index), |(unit, index)| self.effects[unit].get_parameter_name(index))
+    }
+
+    pub fn get_parameter_label (&self, index: u32) -> String {
+        self.locate(index).map_or("".to_string(), |(unit, index)| self.effects[unit].get_parameter_label(index))
+    }
+
+    pub fn get_parameter_text (&self, index: u32) -> String {
+        self.locate(index).map_or("".to_string(), |(unit, index)| self.effects[unit].get_parameter_text(index))
+    }
+
+    pub fn set_sample_rate (&mut self, sample_rate: f32) {
+        for effect in self.effects.iter_mut() {
+            effect.set_sample_rate(sample_rate);
+        }
+    }
+
+    pub fn set_tempo (&mut self, bpm: f32) {
+        for effect in self.effects.iter_mut() {
+            effect.set_tempo(bpm);
+        }
+    }
+
+    /// Whether any unit still has a tail to ring out of silence. A unit is done once its
+    /// input and output stayed silent for longer than the gaps its tail may have.
+    pub fn is_ringing (&self) -> bool {
+        self.effects.iter().enumerate().any(|(unit, effect)| {
+            self.active[unit] && self.silent[unit] <= effect.tail_gap()
+        })
+    }
+
+    #[inline]
+    pub fn process (&mut self, frame: Frame) -> Frame {
+        let mut frame = frame;
+        for (unit, effect) in self.effects.iter_mut().enumerate() {
+            if self.active[unit] {
+                let input_silent = is_silent(&frame);
+                frame = effect.process(frame);
+                self.silent[unit] = if input_silent && is_silent(&frame) {
+                    self.silent[unit].saturating_add(1)
+                } else {
+                    0
+                };
+            }
+        }
+        frame
+    }
+}
+
+#[inline]
+fn is_silent (frame: &Frame) -> bool {
+    frame.l.abs() < SILENCE_THRESHOLD && frame.r.abs() < SILENCE_THRESHOLD
+}
+
+/// Chain shared by the synths: distortion, chorus, delay and reverb.
+pub fn standard_chain () -> EffectChain {
+    EffectChain::default()
+        .with(Box::new(Distortion::default()))
+        .with(Box::new(Chorus::default()))
+        .with(Box::new(Delay::default()))
+        .with(Box::new(Reverb::default()))
+}
+
+/// An effect chain on its own, processing the inputs into the outputs. MIDI is ignored.
+pub struct EffectDevice {
+    chain: EffectChain,
+}
+
+impl EffectDevice {
+    pub fn new (chain: EffectChain) -> Self {
+        EffectDevice {
+            chain: chain,
+        }
+    }
+}
+
+impl Device for EffectDevice {
+    fn run<'a> (&mut self, _events: &[TimedEvent], inputs: Option<AudioBus<'a, f32>>, outputs: Option<AudioBus<'a, f32>>) {
+        let mut outs = match outputs {
+            Some(outs) => outs,
+            None => return,
+        };
+        let (out_left, out_right) = outs.split_at_mut(1);
+        let len = cmp::min(out_left[0].len(), out_right[0].len());
+        for i in 0..len {
+            let frame = match inputs {
+                Some(ref ins) if i < ins[0].len() && i < ins[1].len() => Frame { l: ins[0][i], r: ins[1][i] },
+                _ => Frame::default(),
+            };
+            let frame = self.chain.process(frame);
+            out_left[0][i] = frame.l;
+            out_right[0][i] = frame.r;
+        }
+    }
+
+    fn note_on (&mut self, _note: u8, _velocity: u8) {}
+    fn note_off (&mut self, _note: u8, _velocity: u8) {}
+
+    fn set_sample_rate (&mut self, sample_rate: f32) {
+        self.chain.set_sample_rate(sample_rate);
+    }
+
+    fn set_tempo (&mut self, bpm: f32) {
+        self.chain.set_tempo(bpm);
+    }
+
+    fn get_parameter (&self, index: i32) -> f32 {
+        self.chain.get_parameter(index as u32)
+    }
+
+    fn set_parameter (&mut self, index: i32, value: f32) {
+        self.chain.set_parameter(index as u32, value);
+    }
+
+    fn get_num_parameters (&self) -> i32 {
+        self.chain.get_num_parameters() as i32
+    }
+}
+
+impl DevicePlugin for EffectDevice {
+    fn get_parameter_name (&self, index: i32) -> String {
+        self.chain.get_parameter_name(index as u32)
+    }
+
+    fn get_parameter_label (&self, index: i32) -> String {
+        self.chain.get_parameter_label(index as u32)
+    }
+
+    fn get_parameter_text (&self, index: i32) -> String {
+        self.chain.get_parameter_text(index as u32)
+    }
+}
+
+#[test]
+fn test_chain_parameter_offsets () {
+    struct Gain {
+        params: Vec<f32>,
+    }
+
+    impl Effect for Gain {
+        fn get_num_parameters (&self) -> u32 { self.params.len() as u32 }
+        fn get_parameter (&self, index: u32) -> f32 { self.params[index as usize] }
+        fn set_parameter (&mut self, index: u32, value: f32) { self.params[index as usize] = value }
+        fn get_parameter_name (&self, index: u32) -> String { format!("Gain{}", index) }
+        fn set_sample_rate (&mut self, _sample_rate: f32) {}
+        fn is_active (&self) -> bool { self.params[0] > 0.0 }
+        fn process (&mut self, frame: Frame) -> Frame { frame * self.params[0] }
+    }
+
+    let mut chain = EffectChain::default()
+        .with(Box::new(Gain { params: vec![0.5, 0.0] }))
+        .with(Box::new(Gain { params: vec![0.0] }));
+    assert_eq!(chain.get_num_parameters(), 3);
+    assert_eq!(chain.get_parameter_name(2), "Gain0");
+    assert_eq!(chain.process(Frame { l: 1.0, r: 1.0 }).l, 0.5);
+
+    chain.set_parameter(2, 0.5);
+    assert_eq!(chain.get_parameter(2), 0.5);
+    assert_eq!(chain.process(Frame { l: 1.0, r: 1.0 }).l, 0.25);
+}
+
+#[test]
+fn test_chain_rings_until_tail_ends () {
+    use delay::{Delay, DelayParams};
+    use IndexedEnum;
+
+    let mut delay = Delay::default();
+    delay.set_sample_rate(1000.0);
+    delay.set_parameter(DelayParams::DelayTime.to_index(), 0.5);
+    delay.set_parameter(DelayParams::DelayFeedback.to_index(), 0.0);
+    delay.set_parameter(DelayParams::DelayMix.to_index(), 1.0);
+    let mut chain = EffectChain::default().with(Box::new(delay));
+    assert!(!chain.is_ringing());
+
+    // the single repeat comes 500 samples after the impulse
+    chain.process(Frame { l: 1.0, r: 1.0 });
+    for _ in 0..400 {
+        chain.process(Frame::default());
+    }
+    assert!(chain.is_ringing());
+    for _ in 0..1000 {
+        chain.process(Frame::default());
+    }
+    assert!(!chain.is_ringing());
+}
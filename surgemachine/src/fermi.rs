@@ -2,29 +2,64 @@ use helpers;
 use envelope::ADSREnvelope;
 use envelope::Envelope;
 use oscillator::Oscillator;
+use smoother::Smoother;
+use lfo::{self, Lfo, LfoRate, LfoShape};
+use mod_matrix::{ModOffsets, ModSource};
 use waveform::*;
 use IndexedEnum;
 use frame::Frame;
 use params_bag::ParamsBag;
 use voice::Voice;
 use poly_synth::PolySynth;
+use effect::{self, EffectChain};
 use device::{Device, DevicePlugin};
 
+// LFO pitch modulation at full depth, in semitones
+const LFO_PITCH_RANGE: f32 = 12.0;
+
 type Bag = FermiParamsBag;
 pub type Fermi = PolySynth<FermiVoice>;
 
 impl DevicePlugin for Fermi {
     fn get_parameter_name(&self, param: i32) -> String {
-        format!("{:?}", FermiParams::from_index(param as _))
+        if let Some(effect_param) = self.effect_param(param) {
+            return self.effect_parameter_name(effect_param);
+        }
+        match self.synth_param(param) {
+            Some(synth_param) => format!("{:?}", synth_param),
+            None => format!("{:?}", FermiParams::from_index(param as _)),
+        }
     }
     fn get_parameter_label(&self, param: i32) -> String {
+        if let Some(effect_param) = self.effect_param(param) {
+            return self.effect_parameter_label(effect_param);
+        }
+        if let Some(synth_param) = self.synth_param(param) {
+            return self.synth_parameter_label(synth_param);
+        }
         match FermiParams::from_index(param as _) {
             FermiParams::Osc1Level |
             FermiParams::MasterLevel => "dB".to_string(),
+            FermiParams::PressureToFeedforward |
+            FermiParams::TimbreToFeedforward |
+            FermiParams::VelocityToLevel |
+            FermiParams::VelocityToModIndex |
+            FermiParams::Lfo1Depth |
+            FermiParams::Lfo2Depth => "%".to_string(),
+            FermiParams::Lfo1Delay |
+            FermiParams::Lfo2Delay |
+            FermiParams::Lfo1FadeIn |
+            FermiParams::Lfo2FadeIn => "s".to_string(),
             _ => "".to_string()
         }
     }
     fn get_parameter_text(&self, param: i32) -> String {
+        if let Some(effect_param) = self.effect_param(param) {
+            return self.effect_parameter_text(effect_param);
+        }
+        if let Some(synth_param) = self.synth_param(param) {
+            return self.synth_parameter_text(synth_param);
+        }
         let value = self.get_parameter(param);
         match FermiParams::from_index(param as _) {
             FermiParams::Osc1Waveform |
@@ -33,6 +68,32 @@ impl DevicePlugin for Fermi {
             FermiParams::Osc2RatioCoarse => format!("{}", (value * 32.99).floor()),
             FermiParams::Osc1Level |
             FermiParams::MasterLevel => format!("{:.0}", helpers::control_to_db(value)),
+            FermiParams::PressureToFeedforward |
+            FermiParams::TimbreToFeedforward |
+            FermiParams::VelocityToLevel |
+            FermiParams::VelocityToModIndex |
+            FermiParams::Lfo1Depth |
+            FermiParams::Lfo2Depth => format!("{:.0}", value * 100.0),
+            FermiParams::Lfo1Waveform |
+            FermiParams::Lfo2Waveform => format!("{:?}", LfoShape::from_param(value)),
+            FermiParams::Lfo1Rate => {
+                let sync = self.get_parameter(FermiParams::Lfo1Sync.to_index() as i32) > 0.5;
+                LfoRate::from_controls(value, sync).text()
+            },
+            FermiParams::Lfo2Rate => {
+                let sync = self.get_parameter(FermiParams::Lfo2Sync.to_index() as i32) > 0.5;
+                LfoRate::from_controls(value, sync).text()
+            },
+            FermiParams::Lfo1Sync |
+            FermiParams::Lfo2Sync |
+            FermiParams::Lfo1Retrigger |
+            FermiParams::Lfo2Retrigger => format!("{:?}", value > 0.5),
+            FermiParams::Lfo1Delay |
+            FermiParams::Lfo2Delay |
+            FermiParams::Lfo1FadeIn |
+            FermiParams::Lfo2FadeIn => format!("{:.2}", lfo::control_to_time(value)),
+            FermiParams::Lfo1Destination |
+            FermiParams::Lfo2Destination => format!("{:?}", FermiLfoDestination::from_param(value)),
             _ => format!("{:.3}", value),
         }
     }
@@ -62,7 +123,43 @@ pub enum FermiParams {
 
     Osc1Waveform,
     Osc2Waveform,
-    MasterLevel
+    MasterLevel,
+
+    PressureToFeedforward,
+    TimbreToFeedforward,
+
+    VelocityToLevel,
+    VelocityToModIndex,
+
+    Lfo1Waveform,
+    Lfo1Rate,
+    Lfo1Sync,
+    Lfo1Depth,
+    Lfo1Delay,
+    Lfo1FadeIn,
+    Lfo1Retrigger,
+    Lfo1Destination,
+
+    Lfo2Waveform,
+    Lfo2Rate,
+    Lfo2Sync,
+    Lfo2Depth,
+    Lfo2Delay,
+    Lfo2FadeIn,
+    Lfo2Retrigger,
+    Lfo2Destination,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, IndexedEnum)]
+pub enum FermiLfoDestination {
+    Off,
+    Pitch,
+    Feedback,
+    Feedforward,
+}
+
+impl Default for FermiLfoDestination {
+    fn default () -> Self { FermiLfoDestination::Off }
 }
 
 define_params_bag!(FermiParamsBag, FermiParams, [
@@ -71,6 +168,10 @@ define_params_bag!(FermiParamsBag, FermiParams, [
     0.0, 0.0, 0.1, 0.5,
     0.1, 0.2, 0.5, 0.4,
     0.5, 0.2, 0.5,
+    0.0, 0.0,
+    0.5, 0.5,
+    0.0, 0.5, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0,
+    0.0, 0.5, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0,
 ]);
 
 #[derive(Default)]
@@ -80,37 +181,78 @@ pub struct FermiVoice {
     osc1: Oscillator<SinSq>,
     osc2: Oscillator<SinSq>,
     current_note: Option<u8>,
+    note_freq: f32,
+    osc1_ratio: f32,
+    osc2_ratio: f32,
     osc1_feedback: f32,
     osc2_feedback: f32,
-    osc1_feedforward: f32,
     osc1_output: f32,
     osc2_output: f32,
     velocity: f32,
+    velocity_level: f32,
+    velocity_mod: f32,
+    pressure: f32,
+    timbre: f32,
+    // feedforward control, the matrix offset and log curve get applied after smoothing
+    feedforward: Smoother,
+    expression_feedforward: Smoother,
+    jump_smoothers: bool,
+    lfo1: Lfo,
+    lfo2: Lfo,
+    lfo1_destination: FermiLfoDestination,
+    lfo2_destination: FermiLfoDestination,
+    modulation: ModOffsets<FermiParams>,
 }
 
 impl FermiVoice {
+    /// Working value of `param`: the shared value with this voice's matrix offset added.
+    #[inline]
+    fn param(&self, params: &Bag, param: FermiParams) -> f32 {
+        self.modulation.apply(param, params.get(param))
+    }
+
     fn setup_envelopes(&mut self, params: &Bag, rate: f32) {
-        let a1 = params.get(FermiParams::Osc1Attack);
-        let d1 = params.get(FermiParams::Osc1Decay);
-        let s1 = params.get(FermiParams::Osc1Sustain);
-        let r1 = params.get(FermiParams::Osc1Release);
-        let a2 = params.get(FermiParams::Osc2Attack);
-        let d2 = params.get(FermiParams::Osc2Decay);
-        let s2 = params.get(FermiParams::Osc2Sustain);
-        let r2 = params.get(FermiParams::Osc2Release);
+        let a1 = self.param(params, FermiParams::Osc1Attack);
+        let d1 = self.param(params, FermiParams::Osc1Decay);
+        let s1 = self.param(params, FermiParams::Osc1Sustain);
+        let r1 = self.param(params, FermiParams::Osc1Release);
+        let a2 = self.param(params, FermiParams::Osc2Attack);
+        let d2 = self.param(params, FermiParams::Osc2Decay);
+        let s2 = self.param(params, FermiParams::Osc2Sustain);
+        let r2 = self.param(params, FermiParams::Osc2Release);
         self.env1.set_adsr(rate, a1, d1, s1, r1);
         self.env2.set_adsr(rate, a2, d2, s2, r2);
     }
 
     fn setup_feeds(&mut self, params: &Bag) {
-        self.osc1_feedback = helpers::log_control(params.get(FermiParams::Osc1Feedback));
-        self.osc2_feedback = helpers::log_control(params.get(FermiParams::Osc2Feedback));
-        self.osc1_feedforward = helpers::log_control(params.get(FermiParams::Osc1Feedforward))
+        self.osc1_feedback = helpers::log_control(self.param(params, FermiParams::Osc1Feedback));
+        self.osc2_feedback = helpers::log_control(self.param(params, FermiParams::Osc2Feedback));
+    }
+
+    fn setup_lfos(&mut self, params: &Bag) {
+        self.lfo1.setup(
+            LfoShape::from_param(self.param(params, FermiParams::Lfo1Waveform)),
+            LfoRate::from_controls(self.param(params, FermiParams::Lfo1Rate), self.param(params, FermiParams::Lfo1Sync) > 0.5),
+            self.param(params, FermiParams::Lfo1Depth),
+            lfo::control_to_time(self.param(params, FermiParams::Lfo1Delay)),
+            lfo::control_to_time(self.param(params, FermiParams::Lfo1FadeIn)),
+            self.param(params, FermiParams::Lfo1Retrigger) > 0.5
+        );
+        self.lfo2.setup(
+            LfoShape::from_param(self.param(params, FermiParams::Lfo2Waveform)),
+            LfoRate::from_controls(self.param(params, FermiParams::Lfo2Rate), self.param(params, FermiParams::Lfo2Sync) > 0.5),
+            self.param(params, FermiParams::Lfo2Depth),
+            lfo::control_to_time(self.param(params, FermiParams::Lfo2Delay)),
+            lfo::control_to_time(self.param(params, FermiParams::Lfo2FadeIn)),
+            self.param(params, FermiParams::Lfo2Retrigger) > 0.5
+        );
+        self.lfo1_destination = FermiLfoDestination::from_param(self.param(params, FermiParams::Lfo1Destination));
+        self.lfo2_destination = FermiLfoDestination::from_param(self.param(params, FermiParams::Lfo2Destination));
     }
 
     fn setup_waves(&mut self, params: &Bag) {
-        self.osc1.get_wave_mut().square_mix = params.get(FermiParams::Osc1Waveform);
-        self.osc2.get_wave_mut().square_mix = params.get(FermiParams::Osc2Waveform);
+        self.osc1.get_wave_mut().square_mix = self.param(params, FermiParams::Osc1Waveform);
+        self.osc2.get_wave_mut().square_mix = self.param(params, FermiParams::Osc2Waveform);
     }
 }
 
@@ -118,18 +260,40 @@ impl Voice for FermiVoice {
     type ParamsEnum = FermiParams;
     type Bag = Bag;
     type Depth = f32;
-    type PostParam = f32;
+    type PostParam = Smoother;
+
+    fn prepare_post(params: &Bag, master: &mut Smoother) {
+        master.set_target(helpers::log_control(params.get(FermiParams::MasterLevel)));
+    }
+
+    fn process_post(master: &mut Smoother, frame: Frame) -> Frame {
+        frame * master.process()
+    }
+
+    fn create_effects() -> EffectChain {
+        effect::standard_chain()
+    }
 
-    fn prepare_post(params: &Bag) -> f32 {
-        helpers::log_control(params.get(FermiParams::MasterLevel))
+    fn set_post_smoothing(master: &mut Smoother, sample_rate: f32, time: f32) {
+        master.set_time(sample_rate, time);
     }
 
-    fn process_post(master: &f32, frame: Frame) -> Frame {
-        frame * *master
+    fn jump_post(master: &mut Smoother) {
+        master.jump();
+    }
+
+    fn is_mod_destination(param: FermiParams) -> bool {
+        match param {
+            FermiParams::MasterLevel |
+            FermiParams::VelocityToLevel |
+            FermiParams::VelocityToModIndex => false,
+            _ => true,
+        }
     }
 
     fn init (&mut self, params: &Bag, rate: f32) {
         self.setup_envelopes(params, rate);
+        self.setup_lfos(params);
     }
 
     fn current_note (&self) -> Option<u8> { self.current_note }
@@ -139,10 +303,25 @@ impl Voice for FermiVoice {
         self.env2.is_finished()
     }
 
+    fn level (&self) -> f32 {
+        self.env2.get_value()
+    }
+
+    fn reset (&mut self) {
+        self.current_note = None;
+        self.env1.reset();
+        self.env2.reset();
+        self.osc1_output = 0.0;
+        self.osc2_output = 0.0;
+    }
+
     fn note_on(&mut self, note: u8, velocity: u8) {
         self.current_note = Some(note);
+        self.note_freq = helpers::midi_note_to_hz(note);
         self.env1.trigger();
         self.env2.trigger();
+        self.lfo1.trigger();
+        self.lfo2.trigger();
 
         self.osc1.phase_reset();
         self.osc2.phase_reset();
@@ -150,6 +329,7 @@ impl Voice for FermiVoice {
         self.osc1_output = 0.0;
         self.osc2_output = 0.0;
         self.velocity = velocity as f32 / 127.0;
+        self.jump_smoothers = true;
     }
 
     fn note_off(&mut self, _note: u8, _velocity: u8) {
@@ -158,6 +338,53 @@ impl Voice for FermiVoice {
         self.env2.release();
     }
 
+    fn legato(&mut self, note: u8, _velocity: u8) {
+        self.current_note = Some(note);
+    }
+
+    fn set_pressure(&mut self, pressure: f32) {
+        self.pressure = pressure;
+    }
+
+    fn set_timbre(&mut self, timbre: f32) {
+        self.timbre = timbre;
+    }
+
+    fn set_modulation(&mut self, offsets: &ModOffsets<FermiParams>) {
+        self.modulation = offsets.clone();
+    }
+
+    fn set_smoothing(&mut self, sample_rate: f32, time: f32) {
+        self.feedforward.set_time(sample_rate, time);
+        self.expression_feedforward.set_time(sample_rate, time);
+    }
+
+    fn seed(&mut self, seed: u32) {
+        self.lfo1.seed(seed.wrapping_mul(2) | 1 << 31);
+        self.lfo2.seed(seed.wrapping_mul(2).wrapping_add(1) | 1 << 31);
+    }
+
+    fn set_tempo(&mut self, bpm: f32) {
+        self.lfo1.set_tempo(bpm);
+        self.lfo2.set_tempo(bpm);
+    }
+
+    fn mod_source(&self, source: ModSource) -> f32 {
+        match source {
+            ModSource::Envelope1 => self.env1.get_value(),
+            ModSource::Envelope2 => self.env2.get_value(),
+            ModSource::Lfo1 => self.lfo1.get_value(),
+            ModSource::Lfo2 => self.lfo2.get_value(),
+            _ => 0.0,
+        }
+    }
+
+    fn set_frequency(&mut self, freq: f32) {
+        self.note_freq = freq;
+        self.osc1.set_freq(freq * self.osc1_ratio);
+        self.osc2.set_freq(freq * self.osc2_ratio);
+    }
+
     fn update_param(&mut self, bag: &Bag, param: FermiParams, rate: f32) {
         match param {
             FermiParams::Osc1Attack => self.setup_envelopes(bag, rate),
@@ -170,29 +397,55 @@ impl Voice for FermiVoice {
             FermiParams::Osc2Release => self.setup_envelopes(bag, rate),
             FermiParams::Osc1Feedback => self.setup_feeds(bag),
             FermiParams::Osc2Feedback => self.setup_feeds(bag),
-            FermiParams::Osc1Feedforward => self.setup_feeds(bag),
             FermiParams::Osc1Waveform => self.setup_waves(bag),
             FermiParams::Osc2Waveform => self.setup_waves(bag),
+            FermiParams::Lfo1Waveform |
+            FermiParams::Lfo1Rate |
+            FermiParams::Lfo1Sync |
+            FermiParams::Lfo1Depth |
+            FermiParams::Lfo1Delay |
+            FermiParams::Lfo1FadeIn |
+            FermiParams::Lfo1Retrigger |
+            FermiParams::Lfo1Destination |
+            FermiParams::Lfo2Waveform |
+            FermiParams::Lfo2Rate |
+            FermiParams::Lfo2Sync |
+            FermiParams::Lfo2Depth |
+            FermiParams::Lfo2Delay |
+            FermiParams::Lfo2FadeIn |
+            FermiParams::Lfo2Retrigger |
+            FermiParams::Lfo2Destination => self.setup_lfos(bag),
             _ => (),
         };
     }
 
     fn init_process(&mut self, params: &Bag) -> bool {
         match self.current_note {
-            Some(note) => {
-                let note_freq: f32 = helpers::midi_note_to_hz(note);
-
-                let freq1 = note_freq * helpers::ratio_scalar(
-                    params.get(FermiParams::Osc1RatioCoarse),
-                    params.get(FermiParams::Osc1RatioFine),
+            Some(_) => {
+                self.osc1_ratio = helpers::ratio_scalar(
+                    self.param(params, FermiParams::Osc1RatioCoarse),
+                    self.param(params, FermiParams::Osc1RatioFine),
                 );
-                let freq2 = note_freq * helpers::ratio_scalar(
-                    params.get(FermiParams::Osc2RatioCoarse),
-                    params.get(FermiParams::Osc2RatioFine)
+                self.osc2_ratio = helpers::ratio_scalar(
+                    self.param(params, FermiParams::Osc2RatioCoarse),
+                    self.param(params, FermiParams::Osc2RatioFine)
                 );
 
-                self.osc1.set_freq(freq1);
-                self.osc2.set_freq(freq2);
+                let expression_feedforward =
+                    self.param(params, FermiParams::PressureToFeedforward) * self.pressure +
+                    self.param(params, FermiParams::TimbreToFeedforward) * self.timbre;
+                self.feedforward.set_target(params.get(FermiParams::Osc1Feedforward));
+                self.expression_feedforward.set_target(expression_feedforward);
+                self.velocity_level = helpers::velocity_gain(self.param(params, FermiParams::VelocityToLevel), self.velocity);
+                self.velocity_mod = helpers::velocity_gain(self.param(params, FermiParams::VelocityToModIndex), self.velocity);
+                if self.jump_smoothers {
+                    self.jump_smoothers = false;
+                    self.feedforward.jump();
+                    self.expression_feedforward.jump();
+                }
+
+                let note_freq = self.note_freq;
+                self.set_frequency(note_freq);
                 true
             }
             _ => !self.is_finished()
@@ -203,14 +456,36 @@ impl Voice for FermiVoice {
     fn process_sample(&mut self, timestep: f32) -> Frame {
         const MAGIC: f32 = 13.25;
 
+        let mut pitch = 0.0;
+        let mut feedback = 0.0;
+        let mut feedforward = 0.0;
+        let lfo1 = (self.lfo1.process(timestep), self.lfo1_destination);
+        let lfo2 = (self.lfo2.process(timestep), self.lfo2_destination);
+        for &(value, destination) in [lfo1, lfo2].iter() {
+            match destination {
+                FermiLfoDestination::Off => (),
+                FermiLfoDestination::Pitch => pitch += value * LFO_PITCH_RANGE,
+                FermiLfoDestination::Feedback => feedback += value,
+                FermiLfoDestination::Feedforward => feedforward += value,
+            }
+        }
+        if lfo1.1 == FermiLfoDestination::Pitch || lfo2.1 == FermiLfoDestination::Pitch {
+            let freq = self.note_freq * (pitch / 12.0).exp2();
+            self.osc1.set_freq(freq * self.osc1_ratio);
+            self.osc2.set_freq(freq * self.osc2_ratio);
+        }
+
         let env1 = self.env1.get_value();
         let env2 = self.env2.get_value();
 
-        let feedback1 = self.osc1_output * self.osc1_feedback;
-        let feedback2 = self.osc2_output * self.osc2_feedback;
+        let feedback1 = self.osc1_output * (self.osc1_feedback + feedback).max(0.0);
+        let feedback2 = self.osc2_output * (self.osc2_feedback + feedback).max(0.0);
 
         self.osc1_output = self.osc1.get_offset_value(feedback1) * env1 * MAGIC;
-        let feedforward = self.osc1_output * self.osc1_feedforward;
+        let feedforward_control = self.modulation.apply(FermiParams::Osc1Feedforward, self.feedforward.process());
+        let feedforward = (helpers::log_control(feedforward_control) +
+            self.expression_feedforward.process() + feedforward).max(0.0);
+        let feedforward = self.osc1_output * feedforward * self.velocity_mod;
         self.osc2_output = self.osc2.get_offset_value(feedback2 * MAGIC + feedforward) * env2;
 
         self.env1.process();
@@ -218,9 +493,10 @@ impl Voice for FermiVoice {
         self.osc1.step(timestep);
         self.osc2.step(timestep);
 
+        let output = self.osc2_output * self.velocity_level;
         Frame {
-            l: self.osc2_output,
-            r: self.osc2_output
+            l: output,
+            r: output
         }
     }
 }
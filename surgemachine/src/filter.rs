@@ -0,0 +1,201 @@
+use std::f32::consts::PI;
+use frame::Frame;
+use IndexedEnum;
+
+const MIN_CUTOFF: f32 = 20.0;
+// cutoff range in octaves above MIN_CUTOFF, up to about 20kHz
+pub const CUTOFF_OCTAVES: f32 = 10.0;
+const MAX_CUTOFF_RATIO: f32 = 0.49;
+// `tanh` on the ladder input keeps the resonance from running away
+const LADDER_MAX_FEEDBACK: f32 = 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, IndexedEnum)]
+pub enum FilterMode {
+    Off,
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+    Ladder,
+}
+
+impl Default for FilterMode {
+    fn default () -> FilterMode { FilterMode::Off }
+}
+
+/// Padé approximant of `tan`, within 0.03% of it up to the highest cutoff
+/// and a fraction of the cost, which matters with a cutoff moving every sample.
+#[inline]
+fn fast_tan (x: f32) -> f32 {
+    let x2 = x * x;
+    x * (945.0 - 105.0 * x2 + x2 * x2) / (945.0 - 420.0 * x2 + 15.0 * x2 * x2)
+}
+
+/// Maps a 0.0 to 1.0 control to a cutoff frequency, exponentially from 20Hz.
+pub fn control_to_cutoff (control: f32) -> f32 {
+    MIN_CUTOFF * (control * CUTOFF_OCTAVES).exp2()
+}
+
+#[derive(Default, Clone, Copy)]
+struct SvfState {
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+#[derive(Default, Clone, Copy)]
+struct LadderState {
+    stages: [f32; 4],
+}
+
+/// Stereo resonant filter. The state variable modes follow Andrew Simper's trapezoidal
+/// integrator design, the ladder is a four pole zero-delay feedback lowpass.
+#[derive(Default)]
+pub struct Filter {
+    mode: FilterMode,
+    sample_rate: f32,
+    resonance: f32,
+    // cutoff the coefficients were computed for, zero when they need computing again
+    cutoff: f32,
+    g: f32,
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+    svf: [SvfState; 2],
+    ladder: [LadderState; 2],
+}
+
+impl Filter {
+    pub fn set_sample_rate (&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.cutoff = 0.0;
+    }
+
+    pub fn set_mode (&mut self, mode: FilterMode) {
+        if mode != self.mode {
+            self.mode = mode;
+            self.cutoff = 0.0;
+        }
+    }
+
+    /// Resonance from 0.0 to 1.0, self-oscillating near the top of the range.
+    pub fn set_resonance (&mut self, resonance: f32) {
+        let resonance = resonance.max(0.0).min(1.0);
+        if resonance != self.resonance {
+            self.resonance = resonance;
+            self.cutoff = 0.0;
+        }
+    }
+
+    /// Coefficients are only computed again when the cutoff or the settings above changed.
+    #[inline]
+    pub fn set_cutoff (&mut self, cutoff: f32) {
+        if self.mode == FilterMode::Off {
+            return;
+        }
+        let nyquist_safe = self.sample_rate * MAX_CUTOFF_RATIO;
+        let cutoff = cutoff.max(MIN_CUTOFF).min(nyquist_safe);
+        if cutoff == self.cutoff {
+            return;
+        }
+        self.cutoff = cutoff;
+        self.g = fast_tan(PI * cutoff / self.sample_rate);
+        self.k = match self.mode {
+            FilterMode::Ladder => self.resonance * LADDER_MAX_FEEDBACK,
+            _ => 2.0 - 1.98 * self.resonance,
+        };
+        self.a1 = 1.0 / (1.0 + self.g * (self.g + self.k));
+        self.a2 = self.g * self.a1;
+        self.a3 = self.g * self.a2;
+    }
+
+    pub fn reset (&mut self) {
+        self.svf = Default::default();
+        self.ladder = Default::default();
+    }
+
+    #[inline]
+    pub fn process (&mut self, frame: Frame) -> Frame {
+        match self.mode {
+            FilterMode::Off => frame,
+            FilterMode::Ladder => Frame {
+                l: self.process_ladder(0, frame.l),
+                r: self.process_ladder(1, frame.r),
+            },
+            _ => Frame {
+                l: self.process_svf(0, frame.l),
+                r: self.process_svf(1, frame.r),
+            },
+        }
+    }
+
+    #[inline]
+    fn process_svf (&mut self, channel: usize, input: f32) -> f32 {
+        let state = &mut self.svf[channel];
+        let v3 = input - state.ic2eq;
+        let v1 = self.a1 * state.ic1eq + self.a2 * v3;
+        let v2 = state.ic2eq + self.a2 * state.ic1eq + self.a3 * v3;
+        state.ic1eq = 2.0 * v1 - state.ic1eq;
+        state.ic2eq = 2.0 * v2 - state.ic2eq;
+
+        let low = v2;
+        let band = v1;
+        let high = input - self.k * v1 - v2;
+        match self.mode {
+            FilterMode::HighPass => high,
+            FilterMode::BandPass => band,
+            FilterMode::Notch => low + high,
+            _ => low,
+        }
+    }
+
+    #[inline]
+    fn process_ladder (&mut self, channel: usize, input: f32) -> f32 {
+        let state = &mut self.ladder[channel];
+        let big_g = self.g / (1.0 + self.g);
+
+        // solve the feedback loop for the output of the last stage, then run the stages
+        let mut estimate = 0.0;
+        for stage in state.stages.iter() {
+            estimate = estimate * big_g + stage / (1.0 + self.g);
+        }
+        let g4 = big_g * big_g * big_g * big_g;
+        let output = (g4 * input + estimate) / (1.0 + self.k * g4);
+
+        let mut signal = (input - self.k * output).tanh();
+        for stage in state.stages.iter_mut() {
+            let v = (signal - *stage) * big_g;
+            signal = v + *stage;
+            *stage = signal + v;
+        }
+        signal
+    }
+}
+
+#[test]
+fn test_filter_modes_at_dc () {
+    let settle = |mode: FilterMode| {
+        let mut filter = Filter::default();
+        filter.set_sample_rate(44100.0);
+        filter.set_mode(mode);
+        filter.set_cutoff(1000.0);
+        let mut out = Frame::default();
+        for _ in 0..10000 {
+            out = filter.process(Frame { l: 0.5, r: 0.5 });
+        }
+        out.l
+    };
+    assert_eq!(settle(FilterMode::Off), 0.5);
+    assert!((settle(FilterMode::LowPass) - 0.5).abs() < 0.001);
+    assert!(settle(FilterMode::HighPass).abs() < 0.001);
+    assert!(settle(FilterMode::BandPass).abs() < 0.001);
+    assert!((settle(FilterMode::Ladder) - 0.5f32.tanh()).abs() < 0.001);
+}
+
+#[test]
+fn test_fast_tan_up_to_nyquist () {
+    for i in 1..50 {
+        let x = PI * MAX_CUTOFF_RATIO * i as f32 / 49.0;
+        assert!((fast_tan(x) / x.tan() - 1.0).abs() < 0.0005);
+    }
+}
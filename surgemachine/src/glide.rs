@@ -0,0 +1,44 @@
+const SETTLE_RATIO: f32 = 6.9; // ln(1000), within 0.1% of the distance after the glide time
+
+/// Portamento between notes. Pitch is tracked in semitones, so the one-pole slide
+/// is exponential in frequency.
+#[derive(Default)]
+pub struct Glide {
+    pitch: f32,
+    target: f32,
+    coef: f32,
+}
+
+impl Glide {
+    pub fn set_time (&mut self, sample_rate: f32, time: f32) {
+        let samples = time * sample_rate;
+        self.coef = if samples < 1.0 { 0.0 } else { (-SETTLE_RATIO / samples).exp() };
+    }
+
+    pub fn set_target (&mut self, pitch: f32) {
+        self.target = pitch;
+    }
+
+    pub fn jump (&mut self) {
+        self.pitch = self.target;
+    }
+
+    #[inline]
+    pub fn is_gliding (&self) -> bool {
+        self.pitch != self.target
+    }
+
+    #[inline]
+    pub fn process (&mut self) -> f32 {
+        self.pitch = self.target + (self.pitch - self.target) * self.coef;
+        if (self.pitch - self.target).abs() < 0.001 {
+            self.pitch = self.target;
+        }
+        self.pitch
+    }
+
+    #[inline]
+    pub fn get_value (&self) -> f32 {
+        self.pitch
+    }
+}
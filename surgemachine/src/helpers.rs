@@ -34,9 +34,19 @@ pub fn param_detune(param: f32) -> f32 {
     cents_to_ratio(unit_to_cents(param) * 0.1)
 }
 
+/// Gain for a DX-style velocity sensitivity: full velocity always plays at unity,
+/// full sensitivity scales down to silence at zero velocity.
+pub fn velocity_gain (sensitivity: f32, velocity: f32) -> f32 {
+    1.0 - sensitivity * (1.0 - velocity)
+}
+
 pub fn midi_note_to_hz(note: u8) -> f32 {
+    pitch_to_hz(note as f32)
+}
+
+pub fn pitch_to_hz(pitch: f32) -> f32 {
     const A4: f32 = 440.0;
-    (A4 / 32.0) * ((note as f32 - 9.0) / 12.0).exp2()
+    (A4 / 32.0) * ((pitch - 9.0) / 12.0).exp2()
 }
 
 pub fn time_per_sample (sample_rate: f32) -> f32 {
@@ -0,0 +1,201 @@
+use oscillator::Oscillator;
+use waveform::Dynamic;
+use IndexedEnum;
+
+pub const DEFAULT_TEMPO: f32 = 120.0;
+const MIN_RATE: f32 = 0.02;
+// free running rates span 11 octaves above MIN_RATE, up to about 40Hz
+const RATE_OCTAVES: f32 = 11.0;
+const MAX_DELAY: f32 = 5.0;
+
+/// Waveforms of the LFOs, picked from the fixed shapes of `Dynamic`.
+#[derive(Debug, Clone, Copy, PartialEq, IndexedEnum)]
+pub enum LfoShape {
+    Sine,
+    Saw,
+    Square,
+    SampleHold,
+}
+
+impl LfoShape {
+    fn wave (&self) -> Dynamic {
+        match *self {
+            LfoShape::Sine => Dynamic::Sine,
+            LfoShape::Saw => Dynamic::Saw,
+            LfoShape::Square => Dynamic::Square,
+            LfoShape::SampleHold => Dynamic::SampleHold,
+        }
+    }
+}
+
+/// Note lengths for tempo synced rates, one LFO cycle per note.
+#[derive(Debug, Clone, Copy, IndexedEnum)]
+pub enum LfoDivision {
+    FourBars,
+    TwoBars,
+    Bar,
+    Half,
+    DottedQuarter,
+    Quarter,
+    QuarterTriplet,
+    DottedEighth,
+    Eighth,
+    EighthTriplet,
+    Sixteenth,
+    SixteenthTriplet,
+    ThirtySecond,
+}
+
+impl LfoDivision {
+    pub fn beats (&self) -> f32 {
+        match *self {
+            LfoDivision::FourBars => 16.0,
+            LfoDivision::TwoBars => 8.0,
+            LfoDivision::Bar => 4.0,
+            LfoDivision::Half => 2.0,
+            LfoDivision::DottedQuarter => 1.5,
+            LfoDivision::Quarter => 1.0,
+            LfoDivision::QuarterTriplet => 2.0 / 3.0,
+            LfoDivision::DottedEighth => 0.75,
+            LfoDivision::Eighth => 0.5,
+            LfoDivision::EighthTriplet => 1.0 / 3.0,
+            LfoDivision::Sixteenth => 0.25,
+            LfoDivision::SixteenthTriplet => 1.0 / 6.0,
+            LfoDivision::ThirtySecond => 0.125,
+        }
+    }
+}
+
+pub fn control_to_rate (control: f32) -> f32 {
+    MIN_RATE * (control * RATE_OCTAVES).exp2()
+}
+
+/// Delay and fade-in times from a 0.0 to 1.0 control, in seconds.
+pub fn control_to_time (control: f32) -> f32 {
+    control * control * MAX_DELAY
+}
+
+pub enum LfoRate {
+    Hz(f32),
+    Synced(LfoDivision),
+}
+
+impl LfoRate {
+    /// Rate control from 0.0 to 1.0, picking a note length when synced to the host tempo.
+    pub fn from_controls (rate: f32, sync: bool) -> LfoRate {
+        if sync {
+            LfoRate::Synced(LfoDivision::from_param(rate))
+        } else {
+            LfoRate::Hz(control_to_rate(rate))
+        }
+    }
+
+    pub fn text (&self) -> String {
+        match *self {
+            LfoRate::Hz(freq) => format!("{:.2} Hz", freq),
+            LfoRate::Synced(division) => format!("{:?}", division),
+        }
+    }
+}
+
+/// Bipolar low frequency oscillator with delayed fade-in.
+/// Key retriggered LFOs restart their phase and delay with every note,
+/// free running ones keep their phase and only restart the delay.
+pub struct Lfo {
+    osc: Oscillator<Dynamic>,
+    rate: LfoRate,
+    tempo: f32,
+    depth: f32,
+    delay: f32,
+    fade_in: f32,
+    retrigger: bool,
+    elapsed: f32,
+    value: f32,
+}
+
+impl Default for Lfo {
+    fn default () -> Lfo {
+        Lfo {
+            osc: Oscillator::default(),
+            rate: LfoRate::Hz(1.0),
+            tempo: DEFAULT_TEMPO,
+            depth: 0.0,
+            delay: 0.0,
+            fade_in: 0.0,
+            retrigger: true,
+            elapsed: 0.0,
+            value: 0.0,
+        }
+    }
+}
+
+impl Lfo {
+    pub fn setup (&mut self, shape: LfoShape, rate: LfoRate, depth: f32, delay: f32, fade_in: f32, retrigger: bool) {
+        *self.osc.get_wave_mut() = shape.wave();
+        self.rate = rate;
+        self.depth = depth;
+        self.delay = delay;
+        self.fade_in = fade_in;
+        self.retrigger = retrigger;
+        self.update_freq();
+    }
+
+    pub fn set_tempo (&mut self, tempo: f32) {
+        self.tempo = tempo;
+        self.update_freq();
+    }
+
+    pub fn seed (&mut self, seed: u32) {
+        self.osc.seed(seed);
+    }
+
+    fn update_freq (&mut self) {
+        let freq = match self.rate {
+            LfoRate::Hz(freq) => freq,
+            LfoRate::Synced(division) => self.tempo / 60.0 / division.beats(),
+        };
+        self.osc.set_freq(freq);
+    }
+
+    pub fn trigger (&mut self) {
+        self.elapsed = 0.0;
+        if self.retrigger {
+            self.osc.phase_reset();
+        }
+    }
+
+    #[inline]
+    pub fn process (&mut self, timestep: f32) -> f32 {
+        let fade = if self.elapsed < self.delay {
+            0.0
+        } else if self.elapsed < self.delay + self.fade_in {
+            (self.elapsed - self.delay) / self.fade_in
+        } else {
+            1.0
+        };
+        if fade < 1.0 {
+            self.elapsed += timestep;
+        }
+        self.value = self.osc.get_value() * self.depth * fade;
+        self.osc.step(timestep);
+        self.value
+    }
+
+    /// Output of the last processed sample.
+    #[inline]
+    pub fn get_value (&self) -> f32 {
+        self.value
+    }
+}
+
+#[test]
+fn test_lfo_delay_and_fade_in () {
+    let mut lfo = Lfo::default();
+    lfo.setup(LfoShape::Square, LfoRate::Hz(1.0), 1.0, 0.5, 0.5, true);
+    lfo.trigger();
+    let timestep = 0.01;
+    let values: Vec<f32> = (0..150).map(|_| lfo.process(timestep)).collect();
+    assert!(values[..50].iter().all(|v| *v == 0.0));
+    assert!(values[75].abs() > 0.4 && values[75].abs() < 0.6);
+    assert!(values[110..].iter().all(|v| v.abs() == 1.0));
+}
@@ -5,26 +5,52 @@
 #[macro_use] extern crate surgemachine_macros;
 #[macro_use] mod params_bag;
 
-extern crate rand;
 extern crate smallvec;
 
 pub mod device;
+pub mod midi;
+pub mod cc_map;
+pub mod state;
 pub mod waveform;
+pub mod noise;
+pub mod wavetable;
+pub mod wav;
 pub mod helpers;
 
 mod poly_synth;
 mod voice;
+mod note_stack;
+mod glide;
+mod smoother;
+mod mpe;
 
 mod envelope;
 mod oscillator;
+mod filter;
+mod lfo;
+mod mod_matrix;
+mod effect;
+mod delay;
+mod reverb;
+mod chorus;
+mod distortion;
 mod pendulum;
 mod fermi;
 mod frame;
 
 use device::*;
 
-pub use pendulum::PendulumParams;
-pub use fermi::FermiParams;
+pub use pendulum::{PendulumParams, PendulumLfoDestination, VelocityCurve};
+pub use filter::FilterMode;
+pub use lfo::LfoDivision;
+pub use mod_matrix::ModSource;
+pub use delay::DelayParams;
+pub use reverb::ReverbParams;
+pub use chorus::ChorusParams;
+pub use distortion::{DistortionParams, DistortionCurve, Oversampling};
+pub use fermi::{FermiParams, FermiLfoDestination};
+pub use poly_synth::{SynthParams, VoiceSteal, PlayMode};
+pub use note_stack::NotePriority;
 
 pub trait IndexedEnum {
     const NUM_ITEMS: u32;
@@ -42,11 +68,43 @@ pub trait IndexedEnum {
 pub enum DeviceType {
     Pendulum,
     Fermi,
+    Delay,
+}
+
+impl DeviceType {
+    /// Effects process audio inputs instead of playing notes.
+    pub fn is_effect (&self) -> bool {
+        match *self {
+            DeviceType::Delay => true,
+            _ => false,
+        }
+    }
 }
 
 pub fn create_device (device_type: DeviceType) -> Box<DevicePlugin> {
     match device_type {
         DeviceType::Pendulum => Box::new(pendulum::Pendulum::default()),
         DeviceType::Fermi => Box::new(fermi::Fermi::default()),
+        DeviceType::Delay => {
+            let chain = effect::EffectChain::default().with(Box::new(delay::Delay::default()));
+            let mut device = effect::EffectDevice::new(chain);
+            // the synths' delay starts off, on its own it would do nothing
+            device.set_parameter(DelayParams::DelayMix.to_index() as i32, 0.3);
+            Box::new(device)
+        },
+    }
+}
+
+#[test]
+fn test_parameter_names_are_unique () {
+    use std::collections::HashSet;
+
+    // state refers to parameters by name
+    for device_type in vec![DeviceType::Pendulum, DeviceType::Fermi, DeviceType::Delay] {
+        let device = create_device(device_type);
+        let names: HashSet<String> = (0..device.get_num_parameters())
+            .map(|index| device.get_parameter_name(index))
+            .collect();
+        assert_eq!(names.len(), device.get_num_parameters() as usize);
     }
 }
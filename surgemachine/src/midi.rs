@@ -0,0 +1,199 @@
+/// Decoded MIDI 1.0 message. Channels are zero based, 0 to 15.
+/// http://www.midimountain.com/midi/midi_status.htm
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MidiMessage {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    PolyPressure { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelPressure { channel: u8, pressure: u8 },
+    /// Bend amount from -8192 to 8191, 0 is centered.
+    PitchBend { channel: u8, value: i16 },
+
+    TimeCodeQuarterFrame { value: u8 },
+    SongPosition { beats: u16 },
+    SongSelect { song: u8 },
+    TuneRequest,
+
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    SystemReset,
+}
+
+// release velocity implied by a note-on with zero velocity
+const DEFAULT_RELEASE_VELOCITY: u8 = 64;
+
+/// Number of data bytes following the status byte, `None` for undefined statuses and sysex.
+fn data_length (status: u8) -> Option<usize> {
+    match status & 0xF0 {
+        0x80 | 0x90 | 0xA0 | 0xB0 | 0xE0 => Some(2),
+        0xC0 | 0xD0 => Some(1),
+        _ => match status {
+            0xF1 | 0xF3 => Some(1),
+            0xF2 => Some(2),
+            0xF6 | 0xF8 | 0xFA | 0xFB | 0xFC | 0xFE | 0xFF => Some(0),
+            _ => None,
+        }
+    }
+}
+
+impl MidiMessage {
+    /// Decodes a single complete message. Trailing bytes beyond the message length are ignored,
+    /// so 3-byte host events carrying shorter messages are accepted.
+    pub fn parse (data: &[u8]) -> Option<MidiMessage> {
+        let status = match data.first() {
+            Some(&status) if status >= 0x80 => status,
+            _ => return None,
+        };
+        let len = match data_length(status) {
+            Some(len) if data.len() > len => len,
+            _ => return None,
+        };
+        let mut bytes = [0u8; 2];
+        for (byte, &value) in bytes.iter_mut().zip(data[1..len + 1].iter()) {
+            if value >= 0x80 {
+                return None;
+            }
+            *byte = value;
+        }
+        Some(Self::from_parts(status, bytes))
+    }
+
+    fn from_parts (status: u8, data: [u8; 2]) -> MidiMessage {
+        let channel = status & 0x0F;
+        match status & 0xF0 {
+            0x80 => MidiMessage::NoteOff { channel: channel, note: data[0], velocity: data[1] },
+            0x90 if data[1] == 0 => MidiMessage::NoteOff {
+                channel: channel,
+                note: data[0],
+                velocity: DEFAULT_RELEASE_VELOCITY,
+            },
+            0x90 => MidiMessage::NoteOn { channel: channel, note: data[0], velocity: data[1] },
+            0xA0 => MidiMessage::PolyPressure { channel: channel, note: data[0], pressure: data[1] },
+            0xB0 => MidiMessage::ControlChange { channel: channel, controller: data[0], value: data[1] },
+            0xC0 => MidiMessage::ProgramChange { channel: channel, program: data[0] },
+            0xD0 => MidiMessage::ChannelPressure { channel: channel, pressure: data[0] },
+            0xE0 => MidiMessage::PitchBend {
+                channel: channel,
+                value: ((data[1] as i16) << 7 | data[0] as i16) - 8192,
+            },
+            _ => match status {
+                0xF1 => MidiMessage::TimeCodeQuarterFrame { value: data[0] },
+                0xF2 => MidiMessage::SongPosition { beats: (data[1] as u16) << 7 | data[0] as u16 },
+                0xF3 => MidiMessage::SongSelect { song: data[0] },
+                0xF6 => MidiMessage::TuneRequest,
+                0xF8 => MidiMessage::TimingClock,
+                0xFA => MidiMessage::Start,
+                0xFB => MidiMessage::Continue,
+                0xFC => MidiMessage::Stop,
+                0xFE => MidiMessage::ActiveSensing,
+                0xFF => MidiMessage::SystemReset,
+                _ => unreachable!("status {:x} has no data length", status),
+            }
+        }
+    }
+
+    pub fn channel (&self) -> Option<u8> {
+        match *self {
+            MidiMessage::NoteOff { channel, .. } |
+            MidiMessage::NoteOn { channel, .. } |
+            MidiMessage::PolyPressure { channel, .. } |
+            MidiMessage::ControlChange { channel, .. } |
+            MidiMessage::ProgramChange { channel, .. } |
+            MidiMessage::ChannelPressure { channel, .. } |
+            MidiMessage::PitchBend { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes a raw MIDI byte stream, following running status and
+/// realtime messages interleaved within other messages.
+#[derive(Default)]
+pub struct MidiParser {
+    status: Option<u8>,
+    data: [u8; 2],
+    received: usize,
+}
+
+impl MidiParser {
+    pub fn push (&mut self, byte: u8) -> Option<MidiMessage> {
+        if byte >= 0xF8 {
+            return MidiMessage::parse(&[byte]);
+        }
+
+        if byte >= 0x80 {
+            self.received = 0;
+            match data_length(byte) {
+                Some(0) => {
+                    self.status = None;
+                    return MidiMessage::parse(&[byte]);
+                },
+                Some(_) => self.status = Some(byte),
+                None => self.status = None,
+            }
+            return None;
+        }
+
+        let status = match self.status {
+            Some(status) => status,
+            None => return None,
+        };
+        let len = data_length(status).unwrap_or(0);
+        self.data[self.received] = byte;
+        self.received += 1;
+        if self.received < len {
+            return None;
+        }
+
+        self.received = 0;
+        if status >= 0xF0 {
+            // system common messages don't set running status
+            self.status = None;
+        }
+        Some(MidiMessage::from_parts(status, self.data))
+    }
+}
+
+#[test]
+fn test_parse_channel_messages () {
+    assert_eq!(
+        MidiMessage::parse(&[0x93, 60, 100]),
+        Some(MidiMessage::NoteOn { channel: 3, note: 60, velocity: 100 })
+    );
+    assert_eq!(
+        MidiMessage::parse(&[0x9F, 60, 0]),
+        Some(MidiMessage::NoteOff { channel: 15, note: 60, velocity: DEFAULT_RELEASE_VELOCITY })
+    );
+    assert_eq!(
+        MidiMessage::parse(&[0xE0, 0x00, 0x40]),
+        Some(MidiMessage::PitchBend { channel: 0, value: 0 })
+    );
+    assert_eq!(
+        MidiMessage::parse(&[0xD1, 90, 0]),
+        Some(MidiMessage::ChannelPressure { channel: 1, pressure: 90 })
+    );
+    assert_eq!(MidiMessage::parse(&[0xF8, 0, 0]), Some(MidiMessage::TimingClock));
+    assert_eq!(MidiMessage::parse(&[0x90, 0x80, 0]), None);
+    assert_eq!(MidiMessage::parse(&[60, 100, 0]), None);
+}
+
+#[test]
+fn test_parser_running_status () {
+    let mut parser = MidiParser::default();
+    let messages: Vec<_> = [0x90, 60, 0xF8, 100, 62, 0, 0xB0, 1, 127]
+        .iter()
+        .filter_map(|&byte| parser.push(byte))
+        .collect();
+
+    assert_eq!(messages, vec![
+        MidiMessage::TimingClock,
+        MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 },
+        MidiMessage::NoteOff { channel: 0, note: 62, velocity: DEFAULT_RELEASE_VELOCITY },
+        MidiMessage::ControlChange { channel: 0, controller: 1, value: 127 },
+    ]);
+}
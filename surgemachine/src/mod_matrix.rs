@@ -0,0 +1,109 @@
+use smallvec::SmallVec;
+use IndexedEnum;
+
+pub const MOD_SLOTS: usize = 4;
+// key modulation is centered on middle C and reaches full range five octaves away
+const KEY_CENTER: f32 = 60.0;
+const KEY_RANGE: f32 = 60.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, IndexedEnum)]
+pub enum ModSource {
+    Off,
+    Envelope1,
+    Envelope2,
+    Envelope3,
+    FilterEnvelope,
+    Lfo1,
+    Lfo2,
+    Velocity,
+    Key,
+    ModWheel,
+    Aftertouch,
+}
+
+impl Default for ModSource {
+    fn default () -> ModSource { ModSource::Off }
+}
+
+pub fn key_value (note: u8) -> f32 {
+    ((note as f32 - KEY_CENTER) / KEY_RANGE).max(-1.0).min(1.0)
+}
+
+/// One slot of the matrix, `amount` is bipolar from -1.0 to 1.0 of the full parameter range.
+#[derive(Debug, Clone, Copy)]
+pub struct ModRoute<P> {
+    pub source: ModSource,
+    pub destination: P,
+    pub amount: f32,
+}
+
+/// Matrix offsets of one voice, summed by destination. Voices add them to the working values
+/// of their parameters, after any smoothing.
+#[derive(Clone)]
+pub struct ModOffsets<P> {
+    offsets: SmallVec<[(P, f32); MOD_SLOTS]>,
+}
+
+impl<P> Default for ModOffsets<P> {
+    fn default () -> ModOffsets<P> {
+        ModOffsets { offsets: SmallVec::new() }
+    }
+}
+
+impl<P: IndexedEnum + Copy> ModOffsets<P> {
+    /// Sums what `routes` add to each of their destinations, from the value of their source.
+    pub fn set_routes<F> (&mut self, routes: &[ModRoute<P>], mut source_value: F)
+        where F: FnMut(ModSource) -> f32
+    {
+        self.offsets.clear();
+        for route in routes {
+            let offset = source_value(route.source) * route.amount;
+            let index = route.destination.to_index();
+            match self.offsets.iter_mut().find(|entry| entry.0.to_index() == index) {
+                Some(entry) => entry.1 += offset,
+                None => self.offsets.push((route.destination, offset)),
+            }
+        }
+    }
+
+    pub fn offset (&self, param: P) -> f32 {
+        let index = param.to_index();
+        self.offsets.iter()
+            .find(|entry| entry.0.to_index() == index)
+            .map_or(0.0, |entry| entry.1)
+    }
+
+    /// `value` of `param` with its offset added, clamped once to the parameter range.
+    #[inline]
+    pub fn apply (&self, param: P, value: f32) -> f32 {
+        (value + self.offset(param)).max(0.0).min(1.0)
+    }
+}
+
+#[test]
+fn test_routes_sum_before_clamping () {
+    #[derive(Debug, Clone, Copy, IndexedEnum)]
+    enum TestParams {
+        Cutoff,
+        Level,
+    }
+
+    let routes = [
+        ModRoute { source: ModSource::Velocity, destination: TestParams::Cutoff, amount: 0.5 },
+        ModRoute { source: ModSource::ModWheel, destination: TestParams::Cutoff, amount: -0.25 },
+        ModRoute { source: ModSource::Velocity, destination: TestParams::Level, amount: 1.0 },
+        ModRoute { source: ModSource::ModWheel, destination: TestParams::Level, amount: -0.5 },
+    ];
+    let mut offsets = ModOffsets::default();
+    offsets.set_routes(&routes, |source| match source {
+        ModSource::Velocity => 0.5,
+        ModSource::ModWheel => 1.0,
+        _ => 0.0,
+    });
+    assert_eq!(offsets.apply(TestParams::Cutoff, 0.5), 0.5);
+    // clamping after the first route would leave 0.5
+    assert_eq!(offsets.apply(TestParams::Level, 0.9), 0.9);
+
+    offsets.set_routes(&[], |_| 1.0);
+    assert_eq!(offsets.offset(TestParams::Level), 0.0);
+}
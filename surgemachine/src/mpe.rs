@@ -0,0 +1,126 @@
+const NUM_CHANNELS: usize = 16;
+const LOWER_MANAGER: u8 = 0;
+const UPPER_MANAGER: u8 = 15;
+const MAX_MEMBERS: u8 = 15;
+
+const CC_DATA_ENTRY: u8 = 6;
+const CC_RPN_LSB: u8 = 100;
+const CC_RPN_MSB: u8 = 101;
+
+const RPN_PITCH_BEND_RANGE: u16 = 0;
+const RPN_MPE_CONFIGURATION: u16 = 6;
+const RPN_NULL: u16 = 0x3FFF;
+
+pub const DEFAULT_MEMBER_BEND_RANGE: f32 = 48.0;
+
+/// Members left for the other zone, which also needs its manager channel.
+fn remaining_members (members: u8) -> u8 {
+    if members == 0 { MAX_MEMBERS } else { (MAX_MEMBERS - 1).saturating_sub(members) }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Rpn {
+    PitchBendRange { channel: u8, semitones: u8 },
+    MpeConfiguration { channel: u8, members: u8 },
+}
+
+/// MPE zone layout and the expression last received on each channel.
+/// The lower zone is managed on channel 0 with members from channel 1 upwards,
+/// the upper zone on channel 15 with members from channel 14 downwards.
+pub struct Mpe {
+    lower_members: u8,
+    upper_members: u8,
+    lower_bend_range: f32,
+    upper_bend_range: f32,
+    rpn: [u16; NUM_CHANNELS],
+    pub bend: [f32; NUM_CHANNELS],
+    pub pressure: [f32; NUM_CHANNELS],
+    pub timbre: [f32; NUM_CHANNELS],
+}
+
+impl Default for Mpe {
+    fn default () -> Self {
+        Self {
+            lower_members: 0,
+            upper_members: 0,
+            lower_bend_range: DEFAULT_MEMBER_BEND_RANGE,
+            upper_bend_range: DEFAULT_MEMBER_BEND_RANGE,
+            rpn: [RPN_NULL; NUM_CHANNELS],
+            bend: [0.0; NUM_CHANNELS],
+            pressure: [0.0; NUM_CHANNELS],
+            timbre: [0.5; NUM_CHANNELS],
+        }
+    }
+}
+
+impl Mpe {
+    pub fn is_configured (&self) -> bool {
+        self.lower_members > 0 || self.upper_members > 0
+    }
+
+    fn is_lower_member (&self, channel: u8) -> bool {
+        channel > LOWER_MANAGER && channel <= LOWER_MANAGER + self.lower_members
+    }
+
+    fn is_upper_member (&self, channel: u8) -> bool {
+        channel < UPPER_MANAGER && channel >= UPPER_MANAGER - self.upper_members
+    }
+
+    pub fn is_member (&self, channel: u8) -> bool {
+        self.is_lower_member(channel) || self.is_upper_member(channel)
+    }
+
+    /// Sets the member count of the zone managed on `manager`, shrinking the other zone on overlap.
+    pub fn configure (&mut self, manager: u8, members: u8) {
+        let members = members.min(MAX_MEMBERS);
+        match manager {
+            LOWER_MANAGER => {
+                self.lower_members = members;
+                self.upper_members = self.upper_members.min(remaining_members(members));
+                self.lower_bend_range = DEFAULT_MEMBER_BEND_RANGE;
+            },
+            UPPER_MANAGER => {
+                self.upper_members = members;
+                self.lower_members = self.lower_members.min(remaining_members(members));
+                self.upper_bend_range = DEFAULT_MEMBER_BEND_RANGE;
+            },
+            _ => ()
+        }
+    }
+
+    pub fn member_bend_range (&self, channel: u8) -> f32 {
+        if self.is_upper_member(channel) { self.upper_bend_range } else { self.lower_bend_range }
+    }
+
+    pub fn set_member_bend_range (&mut self, channel: u8, semitones: f32) {
+        if self.is_upper_member(channel) {
+            self.upper_bend_range = semitones;
+        } else if self.is_lower_member(channel) {
+            self.lower_bend_range = semitones;
+        }
+    }
+
+    /// Tracks registered parameter selection, returns the parameter once its value is entered.
+    pub fn control_change (&mut self, channel: u8, controller: u8, value: u8) -> Option<Rpn> {
+        let index = channel as usize;
+        if index >= NUM_CHANNELS {
+            return None;
+        }
+        match controller {
+            CC_RPN_MSB => {
+                self.rpn[index] = (value as u16) << 7 | (self.rpn[index] & 0x7F);
+                None
+            },
+            CC_RPN_LSB => {
+                self.rpn[index] = (self.rpn[index] & !0x7F) | value as u16;
+                None
+            },
+            CC_DATA_ENTRY => match self.rpn[index] {
+                RPN_PITCH_BEND_RANGE => Some(Rpn::PitchBendRange { channel: channel, semitones: value }),
+                RPN_MPE_CONFIGURATION => Some(Rpn::MpeConfiguration { channel: channel, members: value }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
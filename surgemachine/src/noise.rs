@@ -0,0 +1,101 @@
+/// Xorshift generator, small enough for every oscillator to own one.
+#[derive(Debug, Clone, Copy)]
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    pub fn new (seed: u32) -> Rng {
+        // spread nearby seeds apart, the state must never be zero
+        Rng { state: seed.wrapping_mul(0x9E37_79B9) | 1 }
+    }
+
+    #[inline]
+    pub fn next_u32 (&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Uniform value from -1.0 to 1.0.
+    #[inline]
+    pub fn next_bipolar (&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 23) as f32 - 1.0
+    }
+}
+
+impl Default for Rng {
+    fn default () -> Rng {
+        Rng::new(0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoiseColor {
+    White,
+    Pink,
+    Brown,
+    /// White noise held for one oscillator cycle.
+    SampleHold,
+}
+
+/// Noise state advanced once per sample by the oscillator.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Noise {
+    rng: Rng,
+    pink: [f32; 3],
+    brown: f32,
+    value: f32,
+}
+
+impl Noise {
+    pub fn seed (&mut self, seed: u32) {
+        *self = Noise::default();
+        self.rng = Rng::new(seed);
+    }
+
+    /// `wrapped` tells whether the oscillator phase started a new cycle.
+    #[inline]
+    pub fn step (&mut self, color: NoiseColor, wrapped: bool) {
+        self.value = match color {
+            NoiseColor::White => self.rng.next_bipolar(),
+            NoiseColor::Pink => {
+                // Paul Kellet's economy filter, -3dB per octave
+                let white = self.rng.next_bipolar();
+                self.pink[0] = 0.99765 * self.pink[0] + white * 0.0990460;
+                self.pink[1] = 0.96300 * self.pink[1] + white * 0.2965164;
+                self.pink[2] = 0.57000 * self.pink[2] + white * 1.0526913;
+                (self.pink[0] + self.pink[1] + self.pink[2] + white * 0.1848) * 0.11
+            },
+            NoiseColor::Brown => {
+                // leaky integrator, -6dB per octave
+                let white = self.rng.next_bipolar();
+                self.brown = (self.brown + white * 0.02) / 1.02;
+                self.brown * 3.5
+            },
+            NoiseColor::SampleHold => if wrapped { self.rng.next_bipolar() } else { self.value },
+        };
+    }
+
+    #[inline]
+    pub fn value (&self) -> f32 {
+        self.value
+    }
+}
+
+#[test]
+fn test_seeded_noise_repeats () {
+    let mut a = Noise::default();
+    let mut b = Noise::default();
+    a.seed(7);
+    b.seed(7);
+    for _ in 0..1000 {
+        a.step(NoiseColor::Pink, false);
+        b.step(NoiseColor::Pink, false);
+        assert_eq!(a.value(), b.value());
+        assert!(a.value().abs() <= 1.0);
+    }
+}
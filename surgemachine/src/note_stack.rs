@@ -0,0 +1,62 @@
+use IndexedEnum;
+
+const MAX_NOTES: usize = 128;
+
+#[derive(Debug, Clone, Copy, IndexedEnum)]
+pub enum NotePriority {
+    Last,
+    Low,
+    High,
+}
+
+/// Held keys in the order they were pressed, used by the monophonic play modes.
+pub struct NoteStack {
+    notes: [(u8, u8); MAX_NOTES],
+    len: usize,
+}
+
+impl Default for NoteStack {
+    fn default () -> Self {
+        Self {
+            notes: [(0, 0); MAX_NOTES],
+            len: 0,
+        }
+    }
+}
+
+impl NoteStack {
+    pub fn push (&mut self, note: u8, velocity: u8) {
+        self.remove(note);
+        if self.len < MAX_NOTES {
+            self.notes[self.len] = (note, velocity);
+            self.len += 1;
+        }
+    }
+
+    pub fn remove (&mut self, note: u8) {
+        let position = self.notes[..self.len].iter().position(|&(n, _)| n == note);
+        if let Some(index) = position {
+            for i in index..self.len - 1 {
+                self.notes[i] = self.notes[i + 1];
+            }
+            self.len -= 1;
+        }
+    }
+
+    pub fn clear (&mut self) {
+        self.len = 0;
+    }
+
+    pub fn is_empty (&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn select (&self, priority: NotePriority) -> Option<(u8, u8)> {
+        let notes = self.notes[..self.len].iter().cloned();
+        match priority {
+            NotePriority::Last => notes.last(),
+            NotePriority::Low => notes.min_by_key(|&(note, _)| note),
+            NotePriority::High => notes.max_by_key(|&(note, _)| note),
+        }
+    }
+}
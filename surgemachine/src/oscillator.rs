@@ -1,8 +1,12 @@
 use waveform::*;
+use noise::Noise;
 
 pub struct Oscillator<W:Waveform=Dynamic> {
     phase: f32,
     frequency: f32,
+    // phase advanced by the last step
+    increment: f32,
+    noise: Noise,
     wave: W
 }
 
@@ -12,6 +16,8 @@ where W: Default {
         Self {
             phase: 0.0,
             frequency: 0.0,
+            increment: 0.0,
+            noise: Noise::default(),
             wave: W::default()
         }
     }
@@ -20,7 +26,16 @@ where W: Default {
 impl<W: Waveform> Oscillator<W> {
     #[inline]
     pub fn step (&mut self, timestep: f32) -> () {
-        self.phase = (self.phase + timestep * self.frequency).fract();
+        self.increment = timestep * self.frequency;
+        let phase = self.phase + self.increment;
+        if let Some(color) = self.wave.noise() {
+            self.noise.step(color, phase >= 1.0);
+        }
+        self.phase = phase.fract();
+    }
+
+    pub fn seed(&mut self, seed: u32) {
+        self.noise.seed(seed);
     }
 
     pub fn set_freq(&mut self, freq: f32) -> () {
@@ -29,16 +44,22 @@ impl<W: Waveform> Oscillator<W> {
 
     #[inline]
     pub fn get_value (&self) -> f32 {
-        self.wave.value_at_phase(self.phase)
+        if self.wave.noise().is_some() {
+            return self.noise.value();
+        }
+        self.wave.value_with_increment(self.phase, self.increment)
     }
 
     #[inline]
     pub fn get_offset_value (&self, phase_offset: f32) -> f32 {
-        self.wave.value_at_phase((self.phase + phase_offset).fract())
+        if self.wave.noise().is_some() {
+            return self.noise.value();
+        }
+        self.wave.value_with_increment((self.phase + phase_offset).fract(), self.increment)
     }
 
-    pub fn set_wave(&mut self, wave: W) -> () {
-        self.wave = wave
+    pub fn get_wave(&self) -> &W {
+        &self.wave
     }
 
     pub fn get_wave_mut(&mut self) -> &mut W {
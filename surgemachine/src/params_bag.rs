@@ -1,5 +1,5 @@
 pub trait ParamsBag<T: ::IndexedEnum>
-    where Self: Sized
+    where Self: Sized + Clone
 {
     fn get (&self, param: T) -> f32;
     fn set (&mut self, param: T, value: f32);
@@ -8,6 +8,7 @@ pub trait ParamsBag<T: ::IndexedEnum>
 
 macro_rules! define_params_bag {
     ($struct:ident, $params_type:ident, $default:expr) => {
+        #[derive(Clone)]
         pub struct $struct {
             params: [f32; $params_type::NUM_ITEMS as usize]
         }
@@ -2,22 +2,51 @@ use helpers;
 use envelope::ADSREnvelope;
 use envelope::Envelope;
 use oscillator::Oscillator;
+use filter::{self, Filter, FilterMode};
+use lfo::{self, Lfo, LfoRate, LfoShape};
+use mod_matrix::{ModOffsets, ModSource};
+use noise::NoiseColor;
+use smoother::Smoother;
 use waveform::*;
+use wavetable::{TableWave, Wavetable, TABLE_SIZE};
+use state::{StateReader, StateWriter};
+use std::sync::Arc;
+use std::f32::consts::E;
 use IndexedEnum;
 use frame::Frame;
 use params_bag::ParamsBag;
 use voice::Voice;
 use poly_synth::PolySynth;
-use device::{Device, DevicePlugin};
+use effect::{self, EffectChain};
+use device::{self, Device, DevicePlugin};
 
 type Bag = PendulumParamsBag;
+// key tracking is centered on middle C
+const FILTER_KEY_CENTER: u8 = 60;
+// LFO modulation at full depth
+const LFO_PITCH_RANGE: f32 = 12.0;
+const LFO_PHASE_RANGE: f32 = 0.5;
+const LFO_CUTOFF_RANGE: f32 = filter::CUTOFF_OCTAVES * 0.5;
+const STATE_VERSION: u32 = 1;
 pub type Pendulum = PolySynth<PendulumVoice>;
 
 impl DevicePlugin for Pendulum {
     fn get_parameter_name(&self, param: i32) -> String {
-        format!("{:?}", PendulumParams::from_index(param as _))
+        if let Some(effect_param) = self.effect_param(param) {
+            return self.effect_parameter_name(effect_param);
+        }
+        match self.synth_param(param) {
+            Some(synth_param) => format!("{:?}", synth_param),
+            None => format!("{:?}", PendulumParams::from_index(param as _)),
+        }
     }
     fn get_parameter_label(&self, param: i32) -> String {
+        if let Some(effect_param) = self.effect_param(param) {
+            return self.effect_parameter_label(effect_param);
+        }
+        if let Some(synth_param) = self.synth_param(param) {
+            return self.synth_parameter_label(synth_param);
+        }
         match PendulumParams::from_index(param as _) {
             PendulumParams::Osc2Level |
             PendulumParams::Osc3Level |
@@ -25,15 +54,44 @@ impl DevicePlugin for Pendulum {
             PendulumParams::Osc1Detune |
             PendulumParams::Osc2Detune |
             PendulumParams::Osc3Detune => "cents".to_string(),
+            PendulumParams::PressureToLevel |
+            PendulumParams::TimbreToOsc3Level |
+            PendulumParams::Osc1VelocityToLevel |
+            PendulumParams::Osc2VelocityToLevel |
+            PendulumParams::Osc3VelocityToLevel |
+            PendulumParams::Osc1VelocityToAttack |
+            PendulumParams::Osc2VelocityToAttack |
+            PendulumParams::Osc3VelocityToAttack |
+            PendulumParams::Osc1Shape |
+            PendulumParams::Osc2Shape |
+            PendulumParams::Osc3Shape |
+            PendulumParams::FilterKeyTrack |
+            PendulumParams::FilterEnvAmount => "%".to_string(),
+            PendulumParams::FilterCutoff => "Hz".to_string(),
+            PendulumParams::Lfo1Depth |
+            PendulumParams::Lfo2Depth => "%".to_string(),
+            PendulumParams::Lfo1Delay |
+            PendulumParams::Lfo2Delay |
+            PendulumParams::Lfo1FadeIn |
+            PendulumParams::Lfo2FadeIn => "s".to_string(),
             _ => "".to_string()
         }
     }
     fn get_parameter_text(&self, param: i32) -> String {
+        if let Some(effect_param) = self.effect_param(param) {
+            return self.effect_parameter_text(effect_param);
+        }
+        if let Some(synth_param) = self.synth_param(param) {
+            return self.synth_parameter_text(synth_param);
+        }
         let value = self.get_parameter(param);
         match PendulumParams::from_index(param as _) {
             PendulumParams::Osc1Waveform |
             PendulumParams::Osc2Waveform |
-            PendulumParams::Osc3Waveform => format!("{:?}", Dynamic::from_param(value)),
+            PendulumParams::Osc3Waveform => format!("{:?}", Dynamic::from_classic_param(value)),
+            PendulumParams::Osc1WaveVariant |
+            PendulumParams::Osc2WaveVariant |
+            PendulumParams::Osc3WaveVariant => format!("{:?}", WaveVariant::from_param(value)),
             PendulumParams::Osc1RatioCoarse |
             PendulumParams::Osc2RatioCoarse |
             PendulumParams::Osc3RatioCoarse => format!("{}", (value * 32.99).floor()),
@@ -43,10 +101,104 @@ impl DevicePlugin for Pendulum {
             PendulumParams::Osc2Level |
             PendulumParams::Osc3Level |
             PendulumParams::MasterLevel => format!("{:.0}", helpers::control_to_db(value)),
-            PendulumParams::Osc3AM => format!("{:?}", value > 0.5),
+            PendulumParams::Osc3AM |
+            PendulumParams::Osc1Wavetable |
+            PendulumParams::Osc2Wavetable |
+            PendulumParams::Osc3Wavetable => format!("{:?}", value > 0.5),
+            PendulumParams::PressureToLevel |
+            PendulumParams::TimbreToOsc3Level |
+            PendulumParams::Osc1VelocityToLevel |
+            PendulumParams::Osc2VelocityToLevel |
+            PendulumParams::Osc3VelocityToLevel |
+            PendulumParams::Osc1VelocityToAttack |
+            PendulumParams::Osc2VelocityToAttack |
+            PendulumParams::Osc3VelocityToAttack |
+            PendulumParams::Osc1Shape |
+            PendulumParams::Osc2Shape |
+            PendulumParams::Osc3Shape |
+            PendulumParams::FilterKeyTrack => format!("{:.0}", value * 100.0),
+            PendulumParams::FilterEnvAmount => format!("{:.0}", (value * 2.0 - 1.0) * 100.0),
+            PendulumParams::FilterMode => format!("{:?}", FilterMode::from_param(value)),
+            PendulumParams::FilterCutoff => format!("{:.0}", filter::control_to_cutoff(value)),
+            PendulumParams::Lfo1Waveform |
+            PendulumParams::Lfo2Waveform => format!("{:?}", LfoShape::from_param(value)),
+            PendulumParams::Lfo1Rate => {
+                let sync = self.get_parameter(PendulumParams::Lfo1Sync.to_index() as i32) > 0.5;
+                LfoRate::from_controls(value, sync).text()
+            },
+            PendulumParams::Lfo2Rate => {
+                let sync = self.get_parameter(PendulumParams::Lfo2Sync.to_index() as i32) > 0.5;
+                LfoRate::from_controls(value, sync).text()
+            },
+            PendulumParams::Lfo1Sync |
+            PendulumParams::Lfo2Sync |
+            PendulumParams::Lfo1Retrigger |
+            PendulumParams::Lfo2Retrigger => format!("{:?}", value > 0.5),
+            PendulumParams::Lfo1Depth |
+            PendulumParams::Lfo2Depth => format!("{:.0}", value * 100.0),
+            PendulumParams::Lfo1Delay |
+            PendulumParams::Lfo2Delay |
+            PendulumParams::Lfo1FadeIn |
+            PendulumParams::Lfo2FadeIn => format!("{:.2}", lfo::control_to_time(value)),
+            PendulumParams::Lfo1Destination |
+            PendulumParams::Lfo2Destination => format!("{:?}", PendulumLfoDestination::from_param(value)),
+            PendulumParams::VelocityCurve => format!("{:?}", VelocityCurve::from_param(value)),
             _ => format!("{:.3}", value),
         }
     }
+
+    fn set_wavetable(&mut self, osc: usize, table: Option<Arc<Wavetable>>) {
+        for voice in self.voices_mut() {
+            voice.set_table(osc, table.clone());
+        }
+    }
+
+    /// Parameters followed by the full bandwidth frames of each oscillator's table.
+    fn get_state(&self) -> Vec<u8> {
+        let mut params = StateWriter::default();
+        device::write_parameters(self, &mut params);
+        let mut writer = StateWriter::default();
+        writer.write_header(STATE_VERSION);
+        writer.write_bytes(&params.into_inner());
+
+        let voice = self.voices().next();
+        for osc in 0..3 {
+            match voice.and_then(|voice| voice.table(osc)) {
+                Some(table) => {
+                    writer.write_u32(table.frames().len() as u32);
+                    for &sample in table.frames() {
+                        writer.write_f32(sample);
+                    }
+                },
+                None => writer.write_u32(0),
+            }
+        }
+        writer.into_inner()
+    }
+
+    fn set_state(&mut self, data: &[u8]) {
+        let mut reader = StateReader::new(data);
+        if reader.read_header().is_none() {
+            return;
+        }
+
+        if let Some(params) = reader.read_bytes() {
+            device::read_parameters(self, &mut StateReader::new(params));
+        }
+
+        for osc in 0..3 {
+            let len = reader.read_u32().unwrap_or(0) as usize;
+            let mut frames = Vec::with_capacity(len);
+            for _ in 0..len {
+                match reader.read_f32() {
+                    Some(sample) => frames.push(sample),
+                    None => break,
+                }
+            }
+            let table = Wavetable::from_cycles(&frames, TABLE_SIZE).map(Arc::new);
+            self.set_wavetable(osc, table);
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, IndexedEnum)]
@@ -89,6 +241,94 @@ pub enum PendulumParams {
     Osc2Level,
     Osc3Level,
     MasterLevel,
+
+    PressureToLevel,
+    TimbreToOsc3Level,
+
+    VelocityCurve,
+    Osc1VelocityToLevel,
+    Osc2VelocityToLevel,
+    Osc3VelocityToLevel,
+    Osc1VelocityToAttack,
+    Osc2VelocityToAttack,
+    Osc3VelocityToAttack,
+
+    Osc1Wavetable,
+    Osc2Wavetable,
+    Osc3Wavetable,
+    Osc1TablePosition,
+    Osc2TablePosition,
+    Osc3TablePosition,
+
+    Osc1Shape,
+    Osc2Shape,
+    Osc3Shape,
+
+    FilterMode,
+    FilterCutoff,
+    FilterResonance,
+    FilterKeyTrack,
+    FilterEnvAmount,
+    FilterAttack,
+    FilterDecay,
+    FilterSustain,
+    FilterRelease,
+
+    Lfo1Waveform,
+    Lfo1Rate,
+    Lfo1Sync,
+    Lfo1Depth,
+    Lfo1Delay,
+    Lfo1FadeIn,
+    Lfo1Retrigger,
+    Lfo1Destination,
+
+    Lfo2Waveform,
+    Lfo2Rate,
+    Lfo2Sync,
+    Lfo2Depth,
+    Lfo2Delay,
+    Lfo2FadeIn,
+    Lfo2Retrigger,
+    Lfo2Destination,
+
+    Osc1WaveVariant,
+    Osc2WaveVariant,
+    Osc3WaveVariant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, IndexedEnum)]
+pub enum PendulumLfoDestination {
+    Off,
+    Pitch,
+    Level,
+    Osc2Level,
+    Osc3Level,
+    PhaseOffset,
+    FilterCutoff,
+}
+
+impl Default for PendulumLfoDestination {
+    fn default () -> Self { PendulumLfoDestination::Off }
+}
+
+#[derive(Debug, Clone, Copy, IndexedEnum)]
+pub enum VelocityCurve {
+    Linear,
+    Exponential,
+    Logarithmic,
+    Fixed,
+}
+
+impl VelocityCurve {
+    fn apply (&self, velocity: f32) -> f32 {
+        match *self {
+            VelocityCurve::Linear => velocity,
+            VelocityCurve::Exponential => helpers::log_control(velocity),
+            VelocityCurve::Logarithmic => (1.0 + (E - 1.0) * velocity).ln(),
+            VelocityCurve::Fixed => 1.0,
+        }
+    }
 }
 
 define_params_bag!(PendulumParamsBag, PendulumParams, [
@@ -100,6 +340,17 @@ define_params_bag!(PendulumParamsBag, PendulumParams, [
     0.01, 0.1, 0.0, 0.1, // osc3 envelope
     0.5, 0.5, 0.5, 0.0, // detunes + am
     1.0, 1.0, 0.5, // levels
+    0.0, 0.0, // pressure, timbre
+    0.0, 1.0, 1.0, 1.0, // velocity curve and levels
+    0.0, 0.0, 0.0, // velocity to attack
+    0.0, 0.0, 0.0, // wavetables
+    0.0, 0.0, 0.0, // table positions
+    0.5, 0.5, 0.5, // shapes
+    0.0, 1.0, 0.0, 0.0, 0.5, // filter
+    0.01, 0.3, 1.0, 0.2, // filter envelope
+    0.0, 0.5, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, // lfo1
+    0.0, 0.5, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, // lfo2
+    0.0, 0.0, 0.0, // wave variants, classic
 ]);
 
 #[derive(Default)]
@@ -108,50 +359,182 @@ pub struct PendulumVoice {
     osc2: PendulumOsc,
     osc3: PendulumOsc,
     current_note: Option<u8>,
-    osc2_level: f32,
-    osc3_level: f32,
+    note_freq: f32,
+    // level controls, the matrix offsets and log curve get applied after smoothing
+    osc2_level: Smoother,
+    osc3_level: Smoother,
+    filter: Filter,
+    filter_env: ADSREnvelope,
+    // cutoff control before key tracking and envelope
+    filter_cutoff: Smoother,
+    filter_key_track: f32,
+    filter_key_octaves: f32,
+    // envelope depth in octaves
+    filter_env_amount: f32,
+    lfo1: Lfo,
+    lfo2: Lfo,
+    lfo1_destination: PendulumLfoDestination,
+    lfo2_destination: PendulumLfoDestination,
     osc3_am: bool,
+    // first block of a note: velocity gets applied and smoothed values start at their targets
+    new_note: bool,
     velocity: f32,
+    sample_rate: f32,
+    pressure: f32,
+    pressure_gain: f32,
+    timbre: f32,
+    modulation: ModOffsets<PendulumParams>,
 }
 
 impl PendulumVoice {
+    /// Working value of `param`: the shared value with this voice's matrix offset added.
+    #[inline]
+    fn param(&self, params: &Bag, param: PendulumParams) -> f32 {
+        self.modulation.apply(param, params.get(param))
+    }
+
     fn setup_envelopes(&mut self, params: &Bag, rate: f32) {
-        let a1 = params.get(PendulumParams::Osc1Attack);
-        let d1 = params.get(PendulumParams::Osc1Decay);
-        let s1 = params.get(PendulumParams::Osc1Sustain);
-        let r1 = params.get(PendulumParams::Osc1Release);
-        let a2 = params.get(PendulumParams::Osc2Attack);
-        let d2 = params.get(PendulumParams::Osc2Decay);
-        let s2 = params.get(PendulumParams::Osc2Sustain);
-        let r2 = params.get(PendulumParams::Osc2Release);
-        let a3 = params.get(PendulumParams::Osc3Attack);
-        let d3 = params.get(PendulumParams::Osc3Decay);
-        let s3 = params.get(PendulumParams::Osc3Sustain);
-        let r3 = params.get(PendulumParams::Osc3Release);
+        let velocity = VelocityCurve::from_param(self.param(params, PendulumParams::VelocityCurve))
+            .apply(self.velocity);
+        let attack_scale = |amount: f32| 1.0 - amount * velocity;
+
+        let a1 = self.param(params, PendulumParams::Osc1Attack) *
+            attack_scale(self.param(params, PendulumParams::Osc1VelocityToAttack));
+        let d1 = self.param(params, PendulumParams::Osc1Decay);
+        let s1 = self.param(params, PendulumParams::Osc1Sustain);
+        let r1 = self.param(params, PendulumParams::Osc1Release);
+        let a2 = self.param(params, PendulumParams::Osc2Attack) *
+            attack_scale(self.param(params, PendulumParams::Osc2VelocityToAttack));
+        let d2 = self.param(params, PendulumParams::Osc2Decay);
+        let s2 = self.param(params, PendulumParams::Osc2Sustain);
+        let r2 = self.param(params, PendulumParams::Osc2Release);
+        let a3 = self.param(params, PendulumParams::Osc3Attack) *
+            attack_scale(self.param(params, PendulumParams::Osc3VelocityToAttack));
+        let d3 = self.param(params, PendulumParams::Osc3Decay);
+        let s3 = self.param(params, PendulumParams::Osc3Sustain);
+        let r3 = self.param(params, PendulumParams::Osc3Release);
         self.osc1.envelope.set_adsr(rate, a1, d1, s1, r1);
         self.osc2.envelope.set_adsr(rate, a2, d2, s2, r2);
         self.osc3.envelope.set_adsr(rate, a3, d3, s3, r3);
+
+        let fa = self.param(params, PendulumParams::FilterAttack);
+        let fd = self.param(params, PendulumParams::FilterDecay);
+        let fs = self.param(params, PendulumParams::FilterSustain);
+        let fr = self.param(params, PendulumParams::FilterRelease);
+        self.filter_env.set_adsr(rate, fa, fd, fs, fr);
+    }
+
+    fn setup_lfos(&mut self, params: &Bag) {
+        self.lfo1.setup(
+            LfoShape::from_param(self.param(params, PendulumParams::Lfo1Waveform)),
+            LfoRate::from_controls(self.param(params, PendulumParams::Lfo1Rate), self.param(params, PendulumParams::Lfo1Sync) > 0.5),
+            self.param(params, PendulumParams::Lfo1Depth),
+            lfo::control_to_time(self.param(params, PendulumParams::Lfo1Delay)),
+            lfo::control_to_time(self.param(params, PendulumParams::Lfo1FadeIn)),
+            self.param(params, PendulumParams::Lfo1Retrigger) > 0.5
+        );
+        self.lfo2.setup(
+            LfoShape::from_param(self.param(params, PendulumParams::Lfo2Waveform)),
+            LfoRate::from_controls(self.param(params, PendulumParams::Lfo2Rate), self.param(params, PendulumParams::Lfo2Sync) > 0.5),
+            self.param(params, PendulumParams::Lfo2Depth),
+            lfo::control_to_time(self.param(params, PendulumParams::Lfo2Delay)),
+            lfo::control_to_time(self.param(params, PendulumParams::Lfo2FadeIn)),
+            self.param(params, PendulumParams::Lfo2Retrigger) > 0.5
+        );
+        self.lfo1_destination = PendulumLfoDestination::from_param(self.param(params, PendulumParams::Lfo1Destination));
+        self.lfo2_destination = PendulumLfoDestination::from_param(self.param(params, PendulumParams::Lfo2Destination));
+
+        let phase_modulated = self.lfo1_destination == PendulumLfoDestination::PhaseOffset ||
+            self.lfo2_destination == PendulumLfoDestination::PhaseOffset;
+        self.osc1.phase_modulated = phase_modulated;
+        self.osc2.phase_modulated = phase_modulated;
+        self.osc3.phase_modulated = phase_modulated;
+    }
+
+    fn setup_filter(&mut self, params: &Bag, rate: f32) {
+        self.filter.set_sample_rate(rate);
+        self.filter.set_mode(FilterMode::from_param(self.param(params, PendulumParams::FilterMode)));
+        self.filter.set_resonance(self.param(params, PendulumParams::FilterResonance));
+        self.filter_key_track = self.param(params, PendulumParams::FilterKeyTrack);
+        self.filter_env_amount = (self.param(params, PendulumParams::FilterEnvAmount) * 2.0 - 1.0) * filter::CUTOFF_OCTAVES;
+    }
+
+    fn setup_velocity(&mut self, params: &Bag) {
+        let velocity = VelocityCurve::from_param(self.param(params, PendulumParams::VelocityCurve))
+            .apply(self.velocity);
+        self.osc1.velocity_gain = helpers::velocity_gain(self.param(params, PendulumParams::Osc1VelocityToLevel), velocity);
+        self.osc2.velocity_gain = helpers::velocity_gain(self.param(params, PendulumParams::Osc2VelocityToLevel), velocity);
+        // as the AM source osc 3 sets the modulation depth, which the velocity should not scale
+        self.osc3.velocity_gain = if self.param(params, PendulumParams::Osc3AM) > 0.5 {
+            1.0
+        } else {
+            helpers::velocity_gain(self.param(params, PendulumParams::Osc3VelocityToLevel), velocity)
+        };
     }
 
     fn setup_waves(&mut self, params: &Bag) {
-        let w1 = Dynamic::from_param(params.get(PendulumParams::Osc1Waveform));
-        let w2 = Dynamic::from_param(params.get(PendulumParams::Osc2Waveform));
-        let w3 = Dynamic::from_param(params.get(PendulumParams::Osc3Waveform));
+        let w1 = Dynamic::from_controls(self.param(params, PendulumParams::Osc1Waveform), self.param(params, PendulumParams::Osc1WaveVariant));
+        let w2 = Dynamic::from_controls(self.param(params, PendulumParams::Osc2Waveform), self.param(params, PendulumParams::Osc2WaveVariant));
+        let w3 = Dynamic::from_controls(self.param(params, PendulumParams::Osc3Waveform), self.param(params, PendulumParams::Osc3WaveVariant));
         self.osc1.set_wave(w1);
         self.osc2.set_wave(w2);
         self.osc3.set_wave(w3);
+        self.osc1.set_shape(self.param(params, PendulumParams::Osc1Shape));
+        self.osc2.set_shape(self.param(params, PendulumParams::Osc2Shape));
+        self.osc3.set_shape(self.param(params, PendulumParams::Osc3Shape));
+
+        self.osc1.set_table_mode(
+            self.param(params, PendulumParams::Osc1Wavetable) > 0.5,
+            self.param(params, PendulumParams::Osc1TablePosition)
+        );
+        self.osc2.set_table_mode(
+            self.param(params, PendulumParams::Osc2Wavetable) > 0.5,
+            self.param(params, PendulumParams::Osc2TablePosition)
+        );
+        self.osc3.set_table_mode(
+            self.param(params, PendulumParams::Osc3Wavetable) > 0.5,
+            self.param(params, PendulumParams::Osc3TablePosition)
+        );
+    }
+
+    fn osc_mut(&mut self, osc: usize) -> Option<&mut PendulumOsc> {
+        match osc {
+            0 => Some(&mut self.osc1),
+            1 => Some(&mut self.osc2),
+            2 => Some(&mut self.osc3),
+            _ => None,
+        }
+    }
+
+    fn set_table(&mut self, osc: usize, table: Option<Arc<Wavetable>>) {
+        if let Some(osc) = self.osc_mut(osc) {
+            osc.set_table(table);
+        }
+    }
+
+    fn table(&self, osc: usize) -> Option<&Arc<Wavetable>> {
+        let osc = match osc {
+            0 => &self.osc1,
+            1 => &self.osc2,
+            2 => &self.osc3,
+            _ => return None,
+        };
+        osc.osc_l.get_wave().table.table.as_ref()
     }
 }
 
 impl Voice for PendulumVoice {
     type ParamsEnum = PendulumParams;
     type Bag = Bag;
-    type PostParam = f32;
+    type PostParam = Smoother;
     type Depth = f32;
 
     fn init (&mut self, params: &Bag, rate: f32) {
+        self.sample_rate = rate;
         self.setup_envelopes(params, rate);
         self.setup_waves(params);
+        self.setup_filter(params, rate);
+        self.setup_lfos(params);
     }
 
     fn current_note (&self) -> Option<u8> { self.current_note }
@@ -161,12 +544,32 @@ impl Voice for PendulumVoice {
         self.osc3.is_finished()
     }
 
+    fn level (&self) -> f32 {
+        self.osc1.level()
+            .max(self.osc2.level())
+            .max(self.osc3.level())
+    }
+
+    fn reset (&mut self) {
+        self.current_note = None;
+        self.osc1.reset();
+        self.osc2.reset();
+        self.osc3.reset();
+        self.filter_env.reset();
+        self.filter.reset();
+    }
+
     fn note_on(&mut self, note: u8, velocity: u8) {
         self.current_note = Some(note);
+        self.note_freq = helpers::midi_note_to_hz(note);
         self.velocity = (velocity as f32 / 127.0).min(1.0);
+        self.new_note = true;
         self.osc1.trigger();
         self.osc2.trigger();
         self.osc3.trigger();
+        self.filter_env.trigger();
+        self.lfo1.trigger();
+        self.lfo2.trigger();
     }
 
     fn note_off(&mut self, _note: u8, _velocity: u8) {
@@ -174,48 +577,158 @@ impl Voice for PendulumVoice {
         self.osc1.release();
         self.osc2.release();
         self.osc3.release();
+        self.filter_env.release();
+    }
+
+    fn legato(&mut self, note: u8, _velocity: u8) {
+        self.current_note = Some(note);
+    }
+
+    fn set_pressure(&mut self, pressure: f32) {
+        self.pressure = pressure;
     }
-    fn prepare_post(params: &Bag) -> f32 {
-        helpers::log_control(params.get(PendulumParams::MasterLevel))
+
+    fn set_timbre(&mut self, timbre: f32) {
+        self.timbre = timbre;
     }
 
-    fn process_post(data: &f32, frame: Frame) -> Frame {
-        frame * *data
+    fn seed(&mut self, seed: u32) {
+        let seed = seed.wrapping_mul(3);
+        self.osc1.seed(seed);
+        self.osc2.seed(seed.wrapping_add(1));
+        self.osc3.seed(seed.wrapping_add(2));
+        // kept apart from the oscillator seeds of every voice
+        self.lfo1.seed(seed | 1 << 31);
+        self.lfo2.seed(seed.wrapping_add(1) | 1 << 31);
+    }
+
+    fn set_tempo(&mut self, bpm: f32) {
+        self.lfo1.set_tempo(bpm);
+        self.lfo2.set_tempo(bpm);
+    }
+
+    fn mod_source(&self, source: ModSource) -> f32 {
+        match source {
+            ModSource::Envelope1 => self.osc1.envelope.get_value(),
+            ModSource::Envelope2 => self.osc2.envelope.get_value(),
+            ModSource::Envelope3 => self.osc3.envelope.get_value(),
+            ModSource::FilterEnvelope => self.filter_env.get_value(),
+            ModSource::Lfo1 => self.lfo1.get_value(),
+            ModSource::Lfo2 => self.lfo2.get_value(),
+            _ => 0.0,
+        }
+    }
+
+    fn set_modulation(&mut self, offsets: &ModOffsets<PendulumParams>) {
+        self.modulation = offsets.clone();
+    }
+
+    fn set_smoothing(&mut self, sample_rate: f32, time: f32) {
+        self.filter_cutoff.set_time(sample_rate, time);
+        self.osc2_level.set_time(sample_rate, time);
+        self.osc3_level.set_time(sample_rate, time);
+        self.osc1.detune.set_time(sample_rate, time);
+        self.osc2.detune.set_time(sample_rate, time);
+        self.osc3.detune.set_time(sample_rate, time);
+    }
+
+    fn set_frequency(&mut self, freq: f32) {
+        self.note_freq = freq;
+        self.osc1.set_freq(freq);
+        self.osc2.set_freq(freq);
+        self.osc3.set_freq(freq);
+    }
+
+    fn prepare_post(params: &Bag, master: &mut Smoother) {
+        master.set_target(helpers::log_control(params.get(PendulumParams::MasterLevel)));
+    }
+
+    fn process_post(master: &mut Smoother, frame: Frame) -> Frame {
+        frame * master.process()
+    }
+
+    fn create_effects() -> EffectChain {
+        effect::standard_chain()
+    }
+
+    fn set_post_smoothing(master: &mut Smoother, sample_rate: f32, time: f32) {
+        master.set_time(sample_rate, time);
+    }
+
+    fn jump_post(master: &mut Smoother) {
+        master.jump();
+    }
+
+    fn is_mod_destination(param: PendulumParams) -> bool {
+        match param {
+            PendulumParams::MasterLevel |
+            PendulumParams::VelocityCurve |
+            PendulumParams::Osc1VelocityToLevel |
+            PendulumParams::Osc2VelocityToLevel |
+            PendulumParams::Osc3VelocityToLevel |
+            PendulumParams::Osc1VelocityToAttack |
+            PendulumParams::Osc2VelocityToAttack |
+            PendulumParams::Osc3VelocityToAttack => false,
+            _ => true,
+        }
     }
 
     fn init_process(&mut self, params: &Bag) -> bool {
         match self.current_note {
-            Some(note) => {
-                let note_freq: f32 = helpers::midi_note_to_hz(note);
-
-                let freq1 = note_freq * helpers::ratio_scalar(
-                    params.get(PendulumParams::Osc1RatioCoarse),
-                    params.get(PendulumParams::Osc1RatioFine),
+            Some(_) => {
+                let ratio1 = helpers::ratio_scalar(
+                    self.param(params, PendulumParams::Osc1RatioCoarse),
+                    self.param(params, PendulumParams::Osc1RatioFine),
                 );
-                let freq2 = note_freq * helpers::ratio_scalar(
-                    params.get(PendulumParams::Osc2RatioCoarse),
-                    params.get(PendulumParams::Osc2RatioFine)
+                let ratio2 = helpers::ratio_scalar(
+                    self.param(params, PendulumParams::Osc2RatioCoarse),
+                    self.param(params, PendulumParams::Osc2RatioFine)
                 );
-                let freq3 = note_freq * helpers::ratio_scalar(
-                    params.get(PendulumParams::Osc3RatioCoarse),
-                    params.get(PendulumParams::Osc3RatioFine)
+                let ratio3 = helpers::ratio_scalar(
+                    self.param(params, PendulumParams::Osc3RatioCoarse),
+                    self.param(params, PendulumParams::Osc3RatioFine)
                 );
 
-                let detune1 = helpers::param_detune(params.get(PendulumParams::Osc1Detune));
-                let detune2 = helpers::param_detune(params.get(PendulumParams::Osc2Detune));
-                let detune3 = helpers::param_detune(params.get(PendulumParams::Osc3Detune));
+                // smoothed before the matrix offsets get added
+                let detune1 = params.get(PendulumParams::Osc1Detune);
+                let detune2 = params.get(PendulumParams::Osc2Detune);
+                let detune3 = params.get(PendulumParams::Osc3Detune);
+
+                let phase_offset1 = self.param(params, PendulumParams::Osc1PhaseOffset);
+                let phase_offset2 = self.param(params, PendulumParams::Osc2PhaseOffset);
+                let phase_offset3 = self.param(params, PendulumParams::Osc3PhaseOffset);
 
-                let phase_offset1 = params.get(PendulumParams::Osc1PhaseOffset);
-                let phase_offset2 = params.get(PendulumParams::Osc2PhaseOffset);
-                let phase_offset3 = params.get(PendulumParams::Osc3PhaseOffset);
+                self.osc2_level.set_target(params.get(PendulumParams::Osc2Level));
+                self.osc3_level.set_target(params.get(PendulumParams::Osc3Level) +
+                    self.param(params, PendulumParams::TimbreToOsc3Level) * self.timbre);
+                self.osc3_am = self.param(params, PendulumParams::Osc3AM) > 0.5;
+                self.pressure_gain = 1.0 + self.param(params, PendulumParams::PressureToLevel) * self.pressure;
+                self.filter_cutoff.set_target(params.get(PendulumParams::FilterCutoff));
+                self.filter_key_octaves = self.filter_key_track *
+                    (self.note_freq / helpers::midi_note_to_hz(FILTER_KEY_CENTER)).log2();
 
-                self.osc2_level = helpers::log_control(params.get(PendulumParams::Osc2Level));
-                self.osc3_level = helpers::log_control(params.get(PendulumParams::Osc3Level));
-                self.osc3_am = params.get(PendulumParams::Osc3AM) > 0.5;
+                self.osc1.detune_offset = self.modulation.offset(PendulumParams::Osc1Detune);
+                self.osc2.detune_offset = self.modulation.offset(PendulumParams::Osc2Detune);
+                self.osc3.detune_offset = self.modulation.offset(PendulumParams::Osc3Detune);
+                self.osc1.setup(ratio1, detune1, phase_offset1);
+                self.osc2.setup(ratio2, detune2, phase_offset2);
+                self.osc3.setup(ratio3, detune3, phase_offset3);
 
-                self.osc1.setup(freq1, detune1, phase_offset1);
-                self.osc2.setup(freq2, detune2, phase_offset2);
-                self.osc3.setup(freq3, detune3, phase_offset3);
+                if self.new_note {
+                    self.new_note = false;
+                    let rate = self.sample_rate;
+                    self.setup_envelopes(params, rate);
+                    self.setup_velocity(params);
+                    self.osc2_level.jump();
+                    self.osc3_level.jump();
+                    self.osc1.jump_detune();
+                    self.osc2.jump_detune();
+                    self.osc3.jump_detune();
+                    self.filter_cutoff.jump();
+                }
+
+                let note_freq = self.note_freq;
+                self.set_frequency(note_freq);
 
                 true
             }
@@ -224,6 +737,7 @@ impl Voice for PendulumVoice {
     }
 
     fn update_param(&mut self, bag: &Bag, param: PendulumParams, rate: f32) {
+        self.sample_rate = rate;
         match param {
             PendulumParams::Osc1Attack => self.setup_envelopes(bag, rate),
             PendulumParams::Osc1Decay => self.setup_envelopes(bag, rate),
@@ -240,21 +754,114 @@ impl Voice for PendulumVoice {
             PendulumParams::Osc1Waveform => self.setup_waves(bag),
             PendulumParams::Osc2Waveform => self.setup_waves(bag),
             PendulumParams::Osc3Waveform => self.setup_waves(bag),
+            PendulumParams::Osc1WaveVariant => self.setup_waves(bag),
+            PendulumParams::Osc2WaveVariant => self.setup_waves(bag),
+            PendulumParams::Osc3WaveVariant => self.setup_waves(bag),
+            PendulumParams::Osc1Wavetable => self.setup_waves(bag),
+            PendulumParams::Osc2Wavetable => self.setup_waves(bag),
+            PendulumParams::Osc3Wavetable => self.setup_waves(bag),
+            PendulumParams::Osc1TablePosition => self.setup_waves(bag),
+            PendulumParams::Osc2TablePosition => self.setup_waves(bag),
+            PendulumParams::Osc3TablePosition => self.setup_waves(bag),
+            PendulumParams::Osc1Shape => self.setup_waves(bag),
+            PendulumParams::Osc2Shape => self.setup_waves(bag),
+            PendulumParams::Osc3Shape => self.setup_waves(bag),
+            PendulumParams::FilterMode => self.setup_filter(bag, rate),
+            PendulumParams::FilterResonance => self.setup_filter(bag, rate),
+            PendulumParams::FilterKeyTrack => self.setup_filter(bag, rate),
+            PendulumParams::FilterEnvAmount => self.setup_filter(bag, rate),
+            PendulumParams::FilterAttack => self.setup_envelopes(bag, rate),
+            PendulumParams::FilterDecay => self.setup_envelopes(bag, rate),
+            PendulumParams::FilterSustain => self.setup_envelopes(bag, rate),
+            PendulumParams::FilterRelease => self.setup_envelopes(bag, rate),
+            PendulumParams::Lfo1Waveform |
+            PendulumParams::Lfo1Rate |
+            PendulumParams::Lfo1Sync |
+            PendulumParams::Lfo1Depth |
+            PendulumParams::Lfo1Delay |
+            PendulumParams::Lfo1FadeIn |
+            PendulumParams::Lfo1Retrigger |
+            PendulumParams::Lfo1Destination |
+            PendulumParams::Lfo2Waveform |
+            PendulumParams::Lfo2Rate |
+            PendulumParams::Lfo2Sync |
+            PendulumParams::Lfo2Depth |
+            PendulumParams::Lfo2Delay |
+            PendulumParams::Lfo2FadeIn |
+            PendulumParams::Lfo2Retrigger |
+            PendulumParams::Lfo2Destination => self.setup_lfos(bag),
+            PendulumParams::VelocityCurve => {
+                self.setup_envelopes(bag, rate);
+                self.setup_velocity(bag);
+            },
+            PendulumParams::Osc1VelocityToAttack => self.setup_envelopes(bag, rate),
+            PendulumParams::Osc2VelocityToAttack => self.setup_envelopes(bag, rate),
+            PendulumParams::Osc3VelocityToAttack => self.setup_envelopes(bag, rate),
+            PendulumParams::Osc1VelocityToLevel => self.setup_velocity(bag),
+            PendulumParams::Osc2VelocityToLevel => self.setup_velocity(bag),
+            PendulumParams::Osc3VelocityToLevel => self.setup_velocity(bag),
+            PendulumParams::Osc3AM => self.setup_velocity(bag),
             _ => (),
         };
     }
 
     #[inline]
     fn process_sample(&mut self, timestep: f32) -> Frame {
+        let mut pitch = 0.0;
+        let mut level = 1.0;
+        let mut osc2_level = 1.0;
+        let mut osc3_level = 1.0;
+        let mut phase = 0.0;
+        let mut cutoff_octaves = 0.0;
+        let lfo1 = (self.lfo1.process(timestep), self.lfo1_destination);
+        let lfo2 = (self.lfo2.process(timestep), self.lfo2_destination);
+        for &(value, destination) in [lfo1, lfo2].iter() {
+            match destination {
+                PendulumLfoDestination::Off => (),
+                PendulumLfoDestination::Pitch => pitch += value * LFO_PITCH_RANGE,
+                PendulumLfoDestination::Level => level *= 1.0 + value,
+                PendulumLfoDestination::Osc2Level => osc2_level *= 1.0 + value,
+                PendulumLfoDestination::Osc3Level => osc3_level *= 1.0 + value,
+                PendulumLfoDestination::PhaseOffset => phase += value * LFO_PHASE_RANGE,
+                PendulumLfoDestination::FilterCutoff => cutoff_octaves += value * LFO_CUTOFF_RANGE,
+            }
+        }
+
+        let pitch_modulated = lfo1.1 == PendulumLfoDestination::Pitch || lfo2.1 == PendulumLfoDestination::Pitch;
+        let note_freq = self.note_freq * (pitch / 12.0).exp2();
+        if pitch_modulated {
+            self.osc1.set_freq(note_freq);
+            self.osc2.set_freq(note_freq);
+            self.osc3.set_freq(note_freq);
+        }
+        self.osc1.process_detune(note_freq);
+        self.osc2.process_detune(note_freq);
+        self.osc3.process_detune(note_freq);
+        self.osc1.phase_mod = phase;
+        self.osc2.phase_mod = phase;
+        self.osc3.phase_mod = phase;
+
         let s1 = self.osc1.process_sample(timestep);
-        let s2 = self.osc2.process_sample(timestep) * self.osc2_level;
-        let s3 = self.osc3.process_sample(timestep) * self.osc3_level;
+        let osc2_control = self.modulation.apply(PendulumParams::Osc2Level, self.osc2_level.process());
+        let osc3_control = self.modulation.apply(PendulumParams::Osc3Level, self.osc3_level.process());
+        let s2 = self.osc2.process_sample(timestep) * helpers::log_control(osc2_control) * osc2_level;
+        let s3 = self.osc3.process_sample(timestep) * helpers::log_control(osc3_control) * osc3_level;
 
-        (if self.osc3_am {
+        let mix = if self.osc3_am {
             (s1 + s2) * (s3 * 0.5 + 0.5)
         } else {
             s1 + s2 + s3
-        }) * self.velocity
+        };
+
+        let env = self.filter_env.get_value();
+        self.filter_env.process();
+        let octaves = env * self.filter_env_amount + self.filter_key_octaves + cutoff_octaves;
+        let cutoff = filter::control_to_cutoff(
+            self.modulation.apply(PendulumParams::FilterCutoff, self.filter_cutoff.process())
+        ) * octaves.exp2();
+        self.filter.set_cutoff(cutoff);
+
+        self.filter.process(mix) * (self.pressure_gain * level)
     }
 }
 
@@ -268,19 +875,62 @@ impl Default for PendulumOscMode {
     fn default () -> Self { PendulumOscMode::Mono }
 }
 
+/// Either one of the fixed shapes or the oscillator's wavetable.
+#[derive(Clone, Default)]
+struct PendulumWave {
+    shape: Dynamic,
+    // pulse width, triangle to saw morph or saw steepness
+    shape_amount: f32,
+    table: TableWave,
+    use_table: bool,
+}
+
+impl Waveform for PendulumWave {
+    #[inline]
+    fn value_at_phase(&self, phase: f32) -> f32 {
+        if self.use_table {
+            self.table.value_at_phase(phase)
+        } else {
+            self.shape.shaped_value(phase, 0.0, self.shape_amount)
+        }
+    }
+
+    #[inline]
+    fn value_with_increment(&self, phase: f32, increment: f32) -> f32 {
+        if self.use_table {
+            self.table.value_with_increment(phase, increment)
+        } else {
+            self.shape.shaped_value(phase, increment, self.shape_amount)
+        }
+    }
+
+    #[inline]
+    fn noise(&self) -> Option<NoiseColor> {
+        if self.use_table { None } else { self.shape.noise() }
+    }
+}
+
 #[derive(Default)]
 struct PendulumOsc {
     envelope: ADSREnvelope,
-    osc_l: Oscillator,
-    osc_r: Oscillator,
+    osc_l: Oscillator<PendulumWave>,
+    osc_r: Oscillator<PendulumWave>,
+    ratio: f32,
+    velocity_gain: f32,
+    // detune control, the matrix offset gets added after smoothing
+    detune: Smoother,
+    detune_offset: f32,
     phase_offset: f32,
+    // LFO offset added to `phase_offset`, per sample
+    phase_mod: f32,
+    phase_modulated: bool,
     osc_mode: PendulumOscMode
 }
 
 impl PendulumOsc {
     #[inline]
     fn process_sample(&mut self, timestep: f32) -> Frame {
-        let env = self.envelope.get_value();
+        let env = self.envelope.get_value() * self.velocity_gain;
         self.envelope.process();
         match self.osc_mode {
             PendulumOscMode::Mono => {
@@ -295,7 +945,7 @@ impl PendulumOsc {
             PendulumOscMode::MonoOffset => {
                 let f = Frame {
                     l: self.osc_l.get_value(),
-                    r: self.osc_l.get_offset_value(self.phase_offset),
+                    r: self.osc_l.get_offset_value(self.offset()),
                 } * env;
                 self.osc_l.step(timestep);
                 f
@@ -304,7 +954,7 @@ impl PendulumOsc {
             PendulumOscMode::Stereo => {
                 let f = Frame {
                     l: self.osc_l.get_value(),
-                    r: self.osc_r.get_offset_value(self.phase_offset),
+                    r: self.osc_r.get_offset_value(self.offset()),
                 } * env;
                 self.osc_l.step(timestep);
                 self.osc_r.step(timestep);
@@ -313,9 +963,39 @@ impl PendulumOsc {
         }
     }
 
+    #[inline]
+    fn offset(&self) -> f32 {
+        // kept positive for the phase wrapping
+        self.phase_offset + self.phase_mod + 1.0
+    }
+
     fn set_wave(&mut self, wave: Dynamic) {
-        self.osc_l.set_wave(wave);
-        self.osc_r.set_wave(wave);
+        self.osc_l.get_wave_mut().shape = wave;
+        self.osc_r.get_wave_mut().shape = wave;
+    }
+
+    fn set_shape(&mut self, amount: f32) {
+        self.osc_l.get_wave_mut().shape_amount = amount;
+        self.osc_r.get_wave_mut().shape_amount = amount;
+    }
+
+    fn set_table_mode(&mut self, use_table: bool, position: f32) {
+        for osc in [&mut self.osc_l, &mut self.osc_r].iter_mut() {
+            let wave = osc.get_wave_mut();
+            wave.use_table = use_table;
+            wave.table.position = position;
+        }
+    }
+
+    fn seed(&mut self, seed: u32) {
+        let seed = seed.wrapping_mul(2);
+        self.osc_l.seed(seed);
+        self.osc_r.seed(seed.wrapping_add(1));
+    }
+
+    fn set_table(&mut self, table: Option<Arc<Wavetable>>) {
+        self.osc_l.get_wave_mut().table.table = table.clone();
+        self.osc_r.get_wave_mut().table.table = table;
     }
 
     fn trigger(&mut self) {
@@ -324,22 +1004,60 @@ impl PendulumOsc {
         self.osc_r.phase_reset();
     }
 
-    fn setup(&mut self, freq: f32, detune: f32, phase_offset: f32) {
+    fn setup(&mut self, ratio: f32, detune: f32, phase_offset: f32) {
+        self.ratio = ratio;
+        self.detune.set_target(detune);
         self.phase_offset = phase_offset;
-        let detune_off =  (1.0 - detune).abs() < 0.001;
+        // stay in stereo until a ramp back to no detune has finished
+        let detune_off = (1.0 - self.detune_ratio(detune)).abs() < 0.001 && !self.detune.is_ramping();
+
+        self.osc_mode = if !detune_off {
+            PendulumOscMode::Stereo
+        } else if phase_offset == 0.0 && !self.phase_modulated {
+            PendulumOscMode::Mono
+        } else {
+            PendulumOscMode::MonoOffset
+        };
+    }
 
+    fn jump_detune(&mut self) {
+        self.detune.jump();
+        let detune_off = (1.0 - self.detune_ratio(self.detune.get_value())).abs() < 0.001;
         if detune_off {
-            self.osc_l.set_freq(freq);
-            self.osc_r.set_freq(freq);
-            self.osc_mode = if phase_offset == 0.0 {
+            self.osc_mode = if self.phase_offset == 0.0 && !self.phase_modulated {
                 PendulumOscMode::Mono
             } else {
                 PendulumOscMode::MonoOffset
             };
-        } else {
-            self.osc_l.set_freq(freq / detune);
-            self.osc_r.set_freq(freq * detune);
-            self.osc_mode = PendulumOscMode::Stereo;
+        }
+    }
+
+    #[inline]
+    fn detune_ratio(&self, control: f32) -> f32 {
+        helpers::param_detune((control + self.detune_offset).max(0.0).min(1.0))
+    }
+
+    #[inline]
+    fn process_detune(&mut self, note_freq: f32) {
+        if self.detune.is_ramping() {
+            self.detune.process();
+            self.set_freq(note_freq);
+        }
+    }
+
+    #[inline]
+    fn set_freq(&mut self, note_freq: f32) {
+        let freq = note_freq * self.ratio;
+        match self.osc_mode {
+            PendulumOscMode::Stereo => {
+                let detune = self.detune_ratio(self.detune.get_value());
+                self.osc_l.set_freq(freq / detune);
+                self.osc_r.set_freq(freq * detune);
+            },
+            _ => {
+                self.osc_l.set_freq(freq);
+                self.osc_r.set_freq(freq);
+            }
         }
     }
 
@@ -350,4 +1068,78 @@ impl PendulumOsc {
     fn is_finished(&self) -> bool {
         self.envelope.is_finished()
     }
+
+    fn level(&self) -> f32 {
+        self.envelope.get_value()
+    }
+
+    fn reset(&mut self) {
+        self.envelope.reset();
+    }
+}
+
+#[cfg(test)]
+fn render_notes (synth: &mut Pendulum, len: usize) -> Vec<f32> {
+    use device::TimedEvent;
+    use midi::MidiMessage;
+
+    let events = [
+        TimedEvent { offset: 0, message: MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 } },
+        TimedEvent { offset: 100, message: MidiMessage::NoteOn { channel: 0, note: 64, velocity: 100 } },
+        TimedEvent { offset: 1000, message: MidiMessage::NoteOff { channel: 0, note: 60, velocity: 0 } },
+        TimedEvent { offset: 1000, message: MidiMessage::NoteOff { channel: 0, note: 64, velocity: 0 } },
+    ];
+    let mut left = vec![0.0; len];
+    let mut right = vec![0.0; len];
+    synth.run(&events, None, Some([&mut left, &mut right]));
+    left.iter().chain(right.iter()).cloned().collect()
+}
+
+#[test]
+fn test_noise_renders_repeat () {
+    let mut synth = Pendulum::default();
+    synth.set_sample_rate(44100.0);
+    // osc3 defaults to white noise, make it last
+    synth.set_parameter(PendulumParams::Osc3Sustain.to_index() as i32, 1.0);
+
+    let first = render_notes(&mut synth, 44100);
+    assert!(first.iter().any(|sample| *sample != 0.0));
+    assert!(synth.voices().all(|voice| voice.is_finished()));
+    let second = render_notes(&mut synth, 44100);
+    assert!(first == second);
+}
+
+#[test]
+fn test_state_keeps_wavetables () {
+    use std::f32::consts::PI;
+
+    let cycle: Vec<f32> = (0..TABLE_SIZE)
+        .map(|i| (2.0 * PI * i as f32 / TABLE_SIZE as f32).sin())
+        .collect();
+    let mut synth = Pendulum::default();
+    synth.set_wavetable(1, Wavetable::from_cycles(&cycle, TABLE_SIZE).map(Arc::new));
+    synth.set_parameter(PendulumParams::Osc2Wavetable.to_index() as i32, 1.0);
+    let state = synth.get_state();
+
+    let mut restored = Pendulum::default();
+    restored.set_state(&state);
+    assert_eq!(restored.get_parameter(PendulumParams::Osc2Wavetable.to_index() as i32), 1.0);
+    let table = restored.voices().next().and_then(|voice| voice.table(1).cloned());
+    assert_eq!(table.map(|table| table.frames().len()), Some(TABLE_SIZE));
+
+}
+
+#[test]
+fn test_velocity_leaves_am_depth_alone () {
+    let mut bag = Bag::default();
+    bag.set(PendulumParams::Osc3VelocityToLevel, 1.0);
+    let mut voice = PendulumVoice::default();
+    voice.init(&bag, 44100.0);
+    voice.note_on(60, 32);
+    voice.init_process(&bag);
+    assert!(voice.osc3.velocity_gain < 1.0);
+
+    bag.set(PendulumParams::Osc3AM, 1.0);
+    voice.update_param(&bag, PendulumParams::Osc3AM, 44100.0);
+    assert_eq!(voice.osc3.velocity_gain, 1.0);
 }
@@ -1,16 +1,269 @@
 use device::*;
+use midi::MidiMessage;
+use mpe::{Mpe, Rpn};
 use smallvec::*;
 use voice::Voice;
+use note_stack::{NoteStack, NotePriority};
+use glide::Glide;
+use mod_matrix::{self, ModOffsets, ModSource, ModRoute, MOD_SLOTS};
+use effect::EffectChain;
 use params_bag::ParamsBag;
 use helpers;
 use frame::Frame;
 use IndexedEnum;
+use std::cmp;
+use std::cmp::Ordering;
+use std::mem;
+
+pub const MAX_VOICES: usize = 32;
+const STEAL_FADE_TIME: f32 = 0.005;
+const SILENCE_LEVEL: f32 = 0.001;
+const MAX_BEND_RANGE: f32 = 24.0;
+const CC_MOD_WHEEL: u8 = 1;
+const CC_SUSTAIN: u8 = 64;
+const CC_SOSTENUTO: u8 = 66;
+const CC_TIMBRE: u8 = 74;
+// the modulation matrix is evaluated once per block of samples
+const MOD_BLOCK_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, IndexedEnum)]
+pub enum VoiceSteal {
+    Oldest,
+    Quietest,
+    Lowest,
+    Highest,
+    SameNote,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, IndexedEnum)]
+pub enum PlayMode {
+    Poly,
+    Mono,
+    Legato,
+}
+
+#[derive(Debug, Clone, Copy, IndexedEnum)]
+pub enum SynthParams {
+    Polyphony,
+    VoiceSteal,
+    PlayMode,
+    NotePriority,
+    GlideTime,
+    BendRange,
+    ModWheelTarget,
+    ModWheelDepth,
+    Mpe,
+    SmoothingTime,
+    Mod1Source,
+    Mod1Destination,
+    Mod1Amount,
+    Mod2Source,
+    Mod2Destination,
+    Mod2Amount,
+    Mod3Source,
+    Mod3Destination,
+    Mod3Amount,
+    Mod4Source,
+    Mod4Destination,
+    Mod4Amount,
+}
+
+// source, destination and amount of every matrix slot
+const MOD_SLOT_PARAMS: [(SynthParams, SynthParams, SynthParams); MOD_SLOTS] = [
+    (SynthParams::Mod1Source, SynthParams::Mod1Destination, SynthParams::Mod1Amount),
+    (SynthParams::Mod2Source, SynthParams::Mod2Destination, SynthParams::Mod2Amount),
+    (SynthParams::Mod3Source, SynthParams::Mod3Destination, SynthParams::Mod3Amount),
+    (SynthParams::Mod4Source, SynthParams::Mod4Destination, SynthParams::Mod4Amount),
+];
+
+define_params_bag!(SynthParamsBag, SynthParams, [
+    7.0 / 31.0, // polyphony, 8 voices
+    0.0, // voice steal
+    0.0, // play mode
+    0.0, // note priority
+    0.0, // glide time
+    2.0 / 24.0, // bend range
+    0.0, // mod wheel target
+    1.0, // mod wheel depth
+    0.0, // mpe
+    0.2, // smoothing time, 20ms
+    0.0, 0.0, 0.5, // mod slot 1, off
+    0.0, 0.0, 0.5, // mod slot 2, off
+    0.0, 0.0, 0.5, // mod slot 3, off
+    0.0, 0.0, 0.5, // mod slot 4, off
+]);
+
+fn glide_time (value: f32) -> f32 {
+    value * value * 2.0
+}
+
+fn smoothing_time (value: f32) -> f32 {
+    value * 0.1
+}
+
+#[derive(Default)]
+struct VoiceSlot<V: Voice> {
+    voice: V,
+    note: u8,
+    velocity: u8,
+    age: u64,
+    glide: Glide,
+    bend: f32,
+    // member channel and its pitch bend in semitones, when played through MPE
+    channel: Option<u8>,
+    note_bend: f32,
+    pressure: f32,
+    fade: f32,
+    pending: Option<(u8, u8)>,
+    release_pending: bool,
+    // note-off arrived while held by a pedal
+    sustained: bool,
+    // latched by the sostenuto pedal
+    sostenuto: bool,
+    // the voice was handed matrix offsets
+    modulated: bool,
+    // every note on this slot starts its noise from here
+    seed: u32,
+}
+
+impl<V: Voice> VoiceSlot<V> {
+    fn current_note (&self) -> Option<u8> {
+        match self.pending {
+            Some(_) if self.release_pending => None,
+            Some((note, _)) => Some(note),
+            None => self.voice.current_note(),
+        }
+    }
+
+    fn is_finished (&self) -> bool {
+        self.pending.is_none() && self.voice.is_finished()
+    }
+
+    fn init_process (&mut self, params: &V::Bag) -> bool {
+        if self.pending.is_none() {
+            let freq = self.frequency();
+            self.voice.set_frequency(freq);
+        }
+        self.voice.init_process(params) || self.pending.is_some()
+    }
+
+    fn mod_source (&self, source: ModSource, mod_wheel: f32, channel_pressure: f32) -> f32 {
+        match source {
+            ModSource::Velocity => self.velocity as f32 / 127.0,
+            ModSource::Key => mod_matrix::key_value(self.note),
+            ModSource::ModWheel => mod_wheel,
+            ModSource::Aftertouch => self.pressure.max(channel_pressure),
+            source => self.voice.mod_source(source),
+        }
+    }
+
+    #[inline]
+    fn frequency (&self) -> f32 {
+        helpers::pitch_to_hz(self.glide.get_value() + self.bend + self.note_bend)
+    }
+
+    fn set_note (&mut self, note: u8, glide: bool) {
+        self.note = note;
+        self.glide.set_target(note as f32);
+        if !glide {
+            self.glide.jump();
+        }
+    }
+
+    fn legato (&mut self, note: u8, velocity: u8) {
+        match self.pending {
+            Some((_, velocity)) => self.pending = Some((note, velocity)),
+            None => self.voice.legato(note, velocity),
+        }
+    }
+
+    fn note_on (&mut self, note: u8, velocity: u8) {
+        self.release_pending = false;
+        self.sustained = false;
+        self.sostenuto = false;
+        if self.voice.is_finished() || self.voice.level() < SILENCE_LEVEL {
+            self.pending = None;
+            self.start_voice(note, velocity);
+        } else {
+            // fade the stolen voice out before the new note takes over
+            if self.pending.is_none() {
+                self.fade = 1.0;
+            }
+            self.pending = Some((note, velocity));
+        }
+    }
+
+    fn note_off (&mut self, note: u8, velocity: u8) {
+        self.sustained = false;
+        match self.pending {
+            Some(_) => self.release_pending = true,
+            None => self.voice.note_off(note, velocity),
+        }
+    }
+
+    /// Starts the note from a clean voice, so the same notes always render the same.
+    fn start_voice (&mut self, note: u8, velocity: u8) {
+        self.voice.reset();
+        self.voice.seed(self.seed);
+        self.voice.note_on(note, velocity);
+    }
+
+    fn start_pending (&mut self, params: &V::Bag) {
+        if let Some((note, velocity)) = self.pending.take() {
+            self.start_voice(note, velocity);
+            let freq = self.frequency();
+            self.voice.set_frequency(freq);
+            self.voice.init_process(params);
+            if self.release_pending {
+                self.voice.note_off(note, 0);
+                self.release_pending = false;
+            }
+        }
+    }
+}
+
+impl<V: Voice<Depth=f32>> VoiceSlot<V> {
+    #[inline]
+    fn process_sample (&mut self, params: &V::Bag, timestep: f32) -> Frame {
+        if self.pending.is_none() {
+            if self.glide.is_gliding() {
+                self.glide.process();
+                let freq = self.frequency();
+                self.voice.set_frequency(freq);
+            }
+            return self.voice.process_sample(timestep);
+        }
+
+        let frame = self.voice.process_sample(timestep) * self.fade;
+        self.fade -= timestep / STEAL_FADE_TIME;
+        if self.fade <= 0.0 {
+            self.start_pending(params);
+        }
+        frame
+    }
+}
 
 pub struct PolySynth<V: Voice> {
     sample_rate: f32,
-    voices: [V; 8],
+    voices: Vec<VoiceSlot<V>>,
     params: V::Bag,
-    voice_cycle: u8,
+    voice_params: V::Bag,
+    synth_params: SynthParamsBag,
+    post: V::PostParam,
+    effects: EffectChain,
+    voice_cycle: usize,
+    note_counter: u64,
+    note_stack: NoteStack,
+    pitch_bend: f32,
+    mod_wheel: f32,
+    mod_wheel_applied: Option<V::ParamsEnum>,
+    mod_targets: SmallVec<[V::ParamsEnum; MOD_SLOTS]>,
+    sustain: bool,
+    sostenuto: bool,
+    channel_pressure: f32,
+    mpe: Mpe,
+    // nothing rendered since the last silence
+    silent: bool,
 }
 
 impl<V> Default for PolySynth<V>
@@ -20,114 +273,1223 @@ impl<V> Default for PolySynth<V>
 {
     fn default() -> Self {
         let bag: V::Bag = Default::default();
-        let mut voices: [V; 8] = Default::default();
-        for voice in voices.iter_mut() {
-            voice.init(&bag, 1.0);
-        }
-        Self {
+        let voices = (0..MAX_VOICES)
+            .map(|_| {
+                let mut slot: VoiceSlot<V> = Default::default();
+                slot.voice.init(&bag, 1.0);
+                slot
+            })
+            .collect();
+        let mut synth = Self {
             sample_rate: 1.0,
             voices: voices,
+            voice_params: bag.clone(),
             params: bag,
+            synth_params: Default::default(),
+            post: Default::default(),
+            effects: V::create_effects(),
             voice_cycle: 0,
-        }
+            note_counter: 0,
+            note_stack: Default::default(),
+            pitch_bend: 0.0,
+            mod_wheel: 0.0,
+            mod_wheel_applied: None,
+            mod_targets: Default::default(),
+            sustain: false,
+            sostenuto: false,
+            channel_pressure: 0.0,
+            mpe: Default::default(),
+            silent: true,
+        };
+        synth.set_polyphony(V::POLYPHONY);
+        synth.seed_voices(0);
+        synth
     }
 }
 
 impl<V: Voice> PolySynth<V> {
-    fn init_process (&mut self) -> (&V::Bag, SmallVec<[&mut V; 8]>) {
-        let params = &self.params;
-        let mut active_voices: SmallVec<[&mut V; 8]> = Default::default();
-        for voice in self.voices.iter_mut() {
-            if voice.init_process(params) {
-                active_voices.push(voice);
+    pub fn set_polyphony (&mut self, voices: usize) {
+        let voices = cmp::max(1, cmp::min(MAX_VOICES, voices));
+        let value = (voices - 1) as f32 / (MAX_VOICES - 1) as f32;
+        self.synth_params.set(SynthParams::Polyphony, value);
+        self.release_excess_voices();
+    }
+
+    /// Every voice gets its own seed derived from `seed`, applied again on each of its notes.
+    pub fn seed_voices (&mut self, seed: u32) {
+        for (index, slot) in self.voices.iter_mut().enumerate() {
+            slot.seed = seed.wrapping_mul(MAX_VOICES as u32).wrapping_add(index as u32);
+            slot.voice.seed(slot.seed);
+        }
+    }
+
+    pub fn voices (&self) -> impl Iterator<Item=&V> {
+        self.voices.iter().map(|slot| &slot.voice)
+    }
+
+    pub fn voices_mut (&mut self) -> impl Iterator<Item=&mut V> {
+        self.voices.iter_mut().map(|slot| &mut slot.voice)
+    }
+
+    fn polyphony (&self) -> usize {
+        let value = self.synth_params.get(SynthParams::Polyphony).max(0.0).min(1.0);
+        1 + (value * (MAX_VOICES - 1) as f32).round() as usize
+    }
+
+    fn play_mode (&self) -> PlayMode {
+        PlayMode::from_param(self.synth_params.get(SynthParams::PlayMode))
+    }
+
+    fn update_glide_time (&mut self) {
+        let time = glide_time(self.synth_params.get(SynthParams::GlideTime));
+        for slot in self.voices.iter_mut() {
+            slot.glide.set_time(self.sample_rate, time);
+        }
+    }
+
+    fn update_smoothing_time (&mut self) {
+        let time = smoothing_time(self.synth_params.get(SynthParams::SmoothingTime));
+        for slot in self.voices.iter_mut() {
+            slot.voice.set_smoothing(self.sample_rate, time);
+        }
+        V::set_post_smoothing(&mut self.post, self.sample_rate, time);
+    }
+
+    fn update_bend (&mut self) {
+        let range = self.synth_params.get(SynthParams::BendRange) * MAX_BEND_RANGE;
+        let bend = self.pitch_bend * range.round();
+        for slot in self.voices.iter_mut() {
+            slot.bend = bend;
+        }
+    }
+
+    fn mod_wheel_target (&self) -> Option<V::ParamsEnum> {
+        self.param_target(self.synth_params.get(SynthParams::ModWheelTarget))
+    }
+
+    /// Device parameter picked by a control, the lowest values select none.
+    fn param_target (&self, value: f32) -> Option<V::ParamsEnum> {
+        let num = V::ParamsEnum::NUM_ITEMS + 1;
+        let index = cmp::min(num - 1, (value * num as f32).max(0.0) as u32);
+        match index {
+            0 => None,
+            index => Some(V::ParamsEnum::from_index(index - 1)),
+        }
+    }
+
+    /// Matrix destination picked by a control, among the parameters the voices can be modulated on.
+    fn mod_destination (&self, value: f32) -> Option<V::ParamsEnum> {
+        let mut destinations = (0..V::ParamsEnum::NUM_ITEMS)
+            .map(V::ParamsEnum::from_index)
+            .filter(|&param| V::is_mod_destination(param));
+        let num = destinations.clone().count() + 1;
+        let index = cmp::min(num - 1, (value * num as f32).max(0.0) as usize);
+        match index {
+            0 => None,
+            index => destinations.nth(index - 1),
+        }
+    }
+
+    /// Rebuilds the parameters seen by the voices: the user values with the mod wheel applied.
+    fn update_voice_params (&mut self) {
+        let previous = self.mod_wheel_applied;
+        let target = self.mod_wheel_target();
+        self.voice_params = self.params.clone();
+        if let Some(target) = target {
+            let depth = self.synth_params.get(SynthParams::ModWheelDepth) * 2.0 - 1.0;
+            let value = self.params.get(target) + self.mod_wheel * depth;
+            self.voice_params.set(target, value.max(0.0).min(1.0));
+        }
+        self.mod_wheel_applied = target;
+        for &param in previous.iter().chain(target.iter()) {
+            for slot in self.voices.iter_mut() {
+                slot.voice.update_param(&self.voice_params, param, self.sample_rate);
+            }
+        }
+    }
+
+    fn mod_routes (&self) -> SmallVec<[ModRoute<V::ParamsEnum>; MOD_SLOTS]> {
+        MOD_SLOT_PARAMS.iter().filter_map(|&(source, destination, amount)| {
+            let source = ModSource::from_param(self.synth_params.get(source));
+            let destination = self.mod_destination(self.synth_params.get(destination));
+            match (source, destination) {
+                (ModSource::Off, _) | (_, None) => None,
+                (source, Some(destination)) => Some(ModRoute {
+                    source: source,
+                    destination: destination,
+                    amount: self.synth_params.get(amount) * 2.0 - 1.0,
+                }),
+            }
+        }).collect()
+    }
+
+    /// Hands every sounding voice the offsets of the matrix routes for the coming block.
+    /// Voices that go silent or lose their routes are brought back to the shared parameters.
+    fn modulate_voices (&mut self) {
+        let routes = self.mod_routes();
+        let targets = routes.iter().map(|route| route.destination).collect();
+        let previous = mem::replace(&mut self.mod_targets, targets);
+        let mod_wheel = self.mod_wheel;
+        let channel_pressure = self.channel_pressure;
+        let sample_rate = self.sample_rate;
+        let mut offsets = ModOffsets::default();
+
+        for slot in self.voices.iter_mut() {
+            if routes.is_empty() || slot.is_finished() {
+                if slot.modulated {
+                    slot.modulated = false;
+                    slot.voice.set_modulation(&ModOffsets::default());
+                    for &param in previous.iter() {
+                        slot.voice.update_param(&self.voice_params, param, sample_rate);
+                    }
+                }
+                continue;
+            }
+
+            offsets.set_routes(&routes, |source| slot.mod_source(source, mod_wheel, channel_pressure));
+            slot.voice.set_modulation(&offsets);
+            slot.modulated = true;
+            for &param in previous.iter().chain(self.mod_targets.iter()) {
+                slot.voice.update_param(&self.voice_params, param, sample_rate);
+            }
+        }
+    }
+
+    fn release_slot (&mut self, index: usize, note: u8, velocity: u8) {
+        let sustain = self.sustain;
+        let slot = &mut self.voices[index];
+        if sustain || slot.sostenuto {
+            slot.sustained = true;
+        } else {
+            slot.note_off(note, velocity);
+        }
+    }
+
+    fn set_sustain (&mut self, down: bool) {
+        self.sustain = down;
+        if !down {
+            self.release_sustained();
+        }
+    }
+
+    fn set_sostenuto (&mut self, down: bool) {
+        if down == self.sostenuto {
+            return;
+        }
+        self.sostenuto = down;
+        for slot in self.voices.iter_mut() {
+            // only keys held at the moment the pedal goes down are latched
+            slot.sostenuto = down && slot.current_note().is_some() && !slot.sustained;
+        }
+        if !down {
+            self.release_sustained();
+        }
+    }
+
+    fn release_sustained (&mut self) {
+        let sustain = self.sustain;
+        for slot in self.voices.iter_mut() {
+            if slot.sustained && !sustain && !slot.sostenuto {
+                if let Some(note) = slot.current_note() {
+                    slot.note_off(note, 0);
+                }
+                slot.sustained = false;
+            }
+        }
+    }
+
+    /// Releases every note, including those held by the pedals, and lifts both pedals.
+    fn release_all_voices (&mut self) {
+        self.note_stack.clear();
+        self.sustain = false;
+        self.sostenuto = false;
+        for slot in self.voices.iter_mut() {
+            slot.sustained = false;
+            slot.sostenuto = false;
+            if let Some(note) = slot.current_note() {
+                slot.note_off(note, 0);
+            }
+        }
+    }
+
+    fn release_excess_voices (&mut self) {
+        let polyphony = self.polyphony();
+        for slot in self.voices[polyphony..].iter_mut() {
+            if let Some(note) = slot.current_note() {
+                slot.note_off(note, 0);
             }
         }
-        (params, active_voices)
+    }
+
+    pub fn synth_param (&self, index: i32) -> Option<SynthParams> {
+        let index = index as u32;
+        if index >= V::ParamsEnum::NUM_ITEMS && index < V::ParamsEnum::NUM_ITEMS + SynthParams::NUM_ITEMS {
+            Some(SynthParams::from_index(index - V::ParamsEnum::NUM_ITEMS))
+        } else {
+            None
+        }
+    }
+
+    /// Index within the effect chain, whose parameters follow the synth parameters.
+    pub fn effect_param (&self, index: i32) -> Option<u32> {
+        let index = index as u32;
+        let first = V::ParamsEnum::NUM_ITEMS + SynthParams::NUM_ITEMS;
+        if index >= first && index < first + self.effects.get_num_parameters() {
+            Some(index - first)
+        } else {
+            None
+        }
+    }
+
+    pub fn effect_parameter_name (&self, param: u32) -> String {
+        self.effects.get_parameter_name(param)
+    }
+
+    pub fn effect_parameter_label (&self, param: u32) -> String {
+        self.effects.get_parameter_label(param)
+    }
+
+    pub fn effect_parameter_text (&self, param: u32) -> String {
+        self.effects.get_parameter_text(param)
+    }
+
+    pub fn synth_parameter_label (&self, param: SynthParams) -> String {
+        match param {
+            SynthParams::GlideTime |
+            SynthParams::SmoothingTime => "ms".to_string(),
+            SynthParams::BendRange => "semitones".to_string(),
+            SynthParams::ModWheelDepth |
+            SynthParams::Mod1Amount |
+            SynthParams::Mod2Amount |
+            SynthParams::Mod3Amount |
+            SynthParams::Mod4Amount => "%".to_string(),
+            _ => "".to_string()
+        }
+    }
+
+    pub fn synth_parameter_text (&self, param: SynthParams) -> String {
+        let value = self.synth_params.get(param);
+        match param {
+            SynthParams::Polyphony => format!("{}", self.polyphony()),
+            SynthParams::VoiceSteal => format!("{:?}", VoiceSteal::from_param(value)),
+            SynthParams::PlayMode => format!("{:?}", PlayMode::from_param(value)),
+            SynthParams::NotePriority => format!("{:?}", NotePriority::from_param(value)),
+            SynthParams::GlideTime => format!("{:.0}", glide_time(value) * 1000.0),
+            SynthParams::BendRange => format!("{}", (value * MAX_BEND_RANGE).round()),
+            SynthParams::ModWheelTarget => match self.mod_wheel_target() {
+                Some(target) => format!("{:?}", target),
+                None => "Off".to_string(),
+            },
+            SynthParams::ModWheelDepth |
+            SynthParams::Mod1Amount |
+            SynthParams::Mod2Amount |
+            SynthParams::Mod3Amount |
+            SynthParams::Mod4Amount => format!("{:.0}", (value * 2.0 - 1.0) * 100.0),
+            SynthParams::Mpe => format!("{:?}", value > 0.5),
+            SynthParams::SmoothingTime => format!("{:.0}", smoothing_time(value) * 1000.0),
+            SynthParams::Mod1Source |
+            SynthParams::Mod2Source |
+            SynthParams::Mod3Source |
+            SynthParams::Mod4Source => format!("{:?}", ModSource::from_param(value)),
+            SynthParams::Mod1Destination |
+            SynthParams::Mod2Destination |
+            SynthParams::Mod3Destination |
+            SynthParams::Mod4Destination => match self.mod_destination(value) {
+                Some(target) => format!("{:?}", target),
+                None => "Off".to_string(),
+            },
+        }
+    }
+
+    fn init_process<'a> (voices: &'a mut [VoiceSlot<V>], params: &V::Bag) -> SmallVec<[&'a mut VoiceSlot<V>; MAX_VOICES]> {
+        let mut active_voices: SmallVec<[&mut VoiceSlot<V>; MAX_VOICES]> = Default::default();
+        for slot in voices.iter_mut() {
+            if slot.init_process(params) {
+                active_voices.push(slot);
+            }
+        }
+        active_voices
     }
 
     fn is_finished (&self) -> bool {
-        self.voices.iter().fold(true, |acc, voice| {
-            acc && voice.is_finished()
+        self.voices.iter().fold(true, |acc, slot| {
+            acc && slot.is_finished()
         })
     }
+
+    fn start_note (&mut self, index: usize, note: u8, velocity: u8, glide: bool) {
+        self.note_counter += 1;
+        let slot = &mut self.voices[index];
+        slot.age = self.note_counter;
+        slot.set_note(note, glide);
+        slot.velocity = velocity;
+        slot.note_on(note, velocity);
+        slot.channel = None;
+        slot.note_bend = 0.0;
+        slot.pressure = 0.0;
+        slot.voice.set_pressure(self.channel_pressure);
+        slot.voice.set_timbre(0.0);
+    }
+
+    fn mpe_enabled (&self) -> bool {
+        self.synth_params.get(SynthParams::Mpe) > 0.5 && self.mpe.is_configured()
+    }
+
+    fn registered_parameter (&mut self, rpn: Rpn) {
+        match rpn {
+            Rpn::MpeConfiguration { channel, members } => {
+                self.mpe.configure(channel, members);
+                let enabled = if self.mpe.is_configured() { 1.0 } else { 0.0 };
+                self.synth_params.set(SynthParams::Mpe, enabled);
+                self.release_all_voices();
+            },
+            Rpn::PitchBendRange { channel, semitones } => {
+                if self.mpe.is_member(channel) {
+                    self.mpe.set_member_bend_range(channel, semitones as f32);
+                    self.update_note_bend(channel);
+                } else {
+                    let value = (semitones as f32 / MAX_BEND_RANGE).min(1.0);
+                    self.synth_params.set(SynthParams::BendRange, value);
+                    self.update_bend();
+                }
+            },
+        }
+    }
+
+    fn update_note_bend (&mut self, channel: u8) {
+        let bend = self.mpe.bend[channel as usize] * self.mpe.member_bend_range(channel);
+        for slot in self.voices.iter_mut().filter(|slot| slot.channel == Some(channel)) {
+            slot.note_bend = bend;
+        }
+    }
+
+    fn update_note_pressure (&mut self, channel: u8) {
+        let pressure = self.mpe.pressure[channel as usize];
+        let channel_pressure = self.channel_pressure;
+        for slot in self.voices.iter_mut().filter(|slot| slot.channel == Some(channel)) {
+            slot.pressure = pressure;
+            slot.voice.set_pressure(pressure.max(channel_pressure));
+        }
+    }
+
+    fn update_note_timbre (&mut self, channel: u8) {
+        let timbre = self.mpe.timbre[channel as usize] * 2.0 - 1.0;
+        for slot in self.voices.iter_mut().filter(|slot| slot.channel == Some(channel)) {
+            slot.voice.set_timbre(timbre);
+        }
+    }
+
+    /// Handles a message on an MPE member channel, where every note carries its own expression.
+    fn mpe_message (&mut self, message: MidiMessage) {
+        match message {
+            MidiMessage::NoteOn { channel, note, velocity } => {
+                let index = self.allocate_voice(note);
+                self.start_note(index, note, velocity, false);
+                self.voices[index].channel = Some(channel);
+                self.update_note_bend(channel);
+                self.update_note_pressure(channel);
+                self.update_note_timbre(channel);
+            },
+            MidiMessage::NoteOff { channel, note, velocity } => {
+                let held = self.voices.iter().position(|slot| {
+                    slot.channel == Some(channel) && slot.current_note() == Some(note) && !slot.sustained
+                });
+                if let Some(index) = held {
+                    self.release_slot(index, note, velocity);
+                }
+            },
+            MidiMessage::PitchBend { channel, value } => {
+                self.mpe.bend[channel as usize] = value as f32 / 8192.0;
+                self.update_note_bend(channel);
+            },
+            MidiMessage::ChannelPressure { channel, pressure } |
+            MidiMessage::PolyPressure { channel, pressure, .. } => {
+                self.mpe.pressure[channel as usize] = pressure as f32 / 127.0;
+                self.update_note_pressure(channel);
+            },
+            MidiMessage::ControlChange { channel, controller: CC_TIMBRE, value } => {
+                self.mpe.timbre[channel as usize] = value as f32 / 127.0;
+                self.update_note_timbre(channel);
+            },
+            _ => ()
+        }
+    }
+
+    /// Plays the top note of the stack on the first voice. `held` tells whether
+    /// another key was still down, which is when legato mode slides instead of retriggering.
+    fn play_mono_note (&mut self, mode: PlayMode, held: bool) {
+        let priority = NotePriority::from_param(self.synth_params.get(SynthParams::NotePriority));
+        let (note, velocity) = match self.note_stack.select(priority) {
+            Some(selected) => selected,
+            None => return,
+        };
+
+        let sounding = self.voices[0].current_note();
+        if sounding == Some(note) {
+            return;
+        }
+
+        let played_before = self.voices[0].age > 0;
+        let glide = played_before && (mode == PlayMode::Mono || held);
+        if mode == PlayMode::Legato && held && sounding.is_some() {
+            let slot = &mut self.voices[0];
+            slot.set_note(note, glide);
+            slot.legato(note, velocity);
+        } else {
+            self.start_note(0, note, velocity, glide);
+        }
+    }
+
+    fn allocate_voice (&mut self, note: u8) -> usize {
+        let policy = VoiceSteal::from_param(self.synth_params.get(SynthParams::VoiceSteal));
+        let count = self.polyphony();
+
+        if let VoiceSteal::SameNote = policy {
+            let same_note = self.voices[..count].iter()
+                .position(|slot| !slot.is_finished() && slot.note == note);
+            if let Some(index) = same_note {
+                return index;
+            }
+        }
+
+        // starting over from silence allocates like a fresh device, keeping renders repeatable
+        if self.is_finished() {
+            self.voice_cycle = 0;
+        }
+        for i in 0..count {
+            let index = (self.voice_cycle + i) % count;
+            if self.voices[index].is_finished() {
+                self.voice_cycle = (index + 1) % count;
+                return index;
+            }
+        }
+
+        // all voices are busy, prefer stealing from those already released
+        let voices = &self.voices[..count];
+        let any_released = voices.iter().any(|slot| slot.current_note().is_none());
+        let candidates = voices.iter().enumerate()
+            .filter(|&(_, slot)| !any_released || slot.current_note().is_none());
+
+        let stolen = match policy {
+            VoiceSteal::Oldest |
+            VoiceSteal::SameNote => candidates.min_by_key(|&(_, slot)| slot.age),
+            VoiceSteal::Quietest => candidates.min_by(|&(_, a), &(_, b)| {
+                a.voice.level().partial_cmp(&b.voice.level()).unwrap_or(Ordering::Equal)
+            }),
+            VoiceSteal::Lowest => candidates.min_by_key(|&(_, slot)| slot.note),
+            VoiceSteal::Highest => candidates.max_by_key(|&(_, slot)| slot.note),
+        };
+        stolen.map_or(0, |(index, _)| index)
+    }
 }
 
-impl<V:Voice<Depth=f32>> Device for PolySynth<V>
-    where V::ParamsEnum: Copy
-{
-    fn set_parameter (&mut self, index: i32, value: f32) {
-        let param = V::ParamsEnum::from_index(index as u32);
-        self.params.set(param, value);
-        for voice in self.voices.iter_mut() {
-            voice.update_param(&self.params, param, self.sample_rate)
+impl<V:Voice<Depth=f32>> PolySynth<V> {
+    fn render (&mut self, outs: &mut AudioBus<f32>, start: usize, end: usize) {
+        // effects keep running without voices, to let their tails ring out
+        if self.is_finished() && !self.effects.is_ringing() {
+            let (left, right) = outs.split_at_mut(1);
+            let mut segment: AudioBus<f32> = [&mut left[0][start..end], &mut right[0][start..end]];
+            for (left_sample, right_sample) in helpers::frame_iter(&mut segment) {
+                *left_sample = 0.0;
+                *right_sample = 0.0;
+            }
+            self.silent = true;
+            return;
+        }
+
+        if self.mod_targets.is_empty() && self.mod_routes().is_empty() {
+            self.render_block(outs, start, end);
+            return;
+        }
+        let mut block_start = start;
+        while block_start < end {
+            let block_end = cmp::min(block_start + MOD_BLOCK_SIZE, end);
+            self.modulate_voices();
+            self.render_block(outs, block_start, block_end);
+            block_start = block_end;
         }
     }
 
-    fn run (&mut self, _inputs: Option<AudioBus<f32>>, outputs: Option<AudioBus<f32>>) {
+    fn render_block (&mut self, outs: &mut AudioBus<f32>, start: usize, end: usize) {
+        let (left, right) = outs.split_at_mut(1);
+        let mut segment: AudioBus<f32> = [&mut left[0][start..end], &mut right[0][start..end]];
+
         let timestep = helpers::time_per_sample(self.sample_rate);
+        let params = &self.voice_params;
+        let post = &mut self.post;
+        let effects = &mut self.effects;
+        let mut active_voices = Self::init_process(&mut self.voices, params);
+        V::prepare_post(params, post);
+        if self.silent {
+            // nothing to ramp from after silence
+            V::jump_post(post);
+            self.silent = false;
+        }
+
+        for (left_sample, right_sample) in helpers::frame_iter(&mut segment) {
+            let signal = active_voices.iter_mut()
+                .map(|slot| slot.process_sample(params, timestep))
+                .sum::<Frame>();
 
-        if !self.is_finished() {
-            let (params, mut active_voices) = self.init_process();
-            let postproc_data = V::prepare_post(params);
+            let signal = V::process_post(post, signal);
+            let signal = effects.process(signal);
 
-            if let Some(mut outs) = outputs {
-                for (left_sample, right_sample) in helpers::frame_iter(&mut outs) {
-                    let signal = active_voices.iter_mut()
-                        .map(|voice| voice.process_sample(timestep))
-                        .sum::<Frame>();
+            *left_sample = signal.l;
+            *right_sample = signal.r;
+        }
+    }
+}
 
-                    let signal = V::process_post(&postproc_data, signal);
+impl<V:Voice<Depth=f32>> Device for PolySynth<V> {
+    fn set_parameter (&mut self, index: i32, value: f32) {
+        if let Some(param) = self.effect_param(index) {
+            self.effects.set_parameter(param, value);
+            return;
+        }
+        if let Some(param) = self.synth_param(index) {
+            self.synth_params.set(param, value);
+            match param {
+                SynthParams::Polyphony => self.release_excess_voices(),
+                SynthParams::PlayMode => self.release_all_voices(),
+                SynthParams::GlideTime => self.update_glide_time(),
+                SynthParams::SmoothingTime => self.update_smoothing_time(),
+                SynthParams::BendRange => self.update_bend(),
+                SynthParams::ModWheelTarget |
+                SynthParams::ModWheelDepth => self.update_voice_params(),
+                SynthParams::Mpe => {
+                    if value > 0.5 && !self.mpe.is_configured() {
+                        // a single lower zone over all channels, as if configured by the controller
+                        self.mpe.configure(0, 15);
+                    }
+                    self.release_all_voices();
+                },
+                _ => ()
+            }
+            return;
+        }
 
-                    *left_sample = signal.l;
-                    *right_sample = signal.r;
+        let param = V::ParamsEnum::from_index(index as u32);
+        self.params.set(param, value);
+        self.update_voice_params();
+        for slot in self.voices.iter_mut() {
+            slot.voice.update_param(&self.voice_params, param, self.sample_rate)
+        }
+    }
+
+    fn run (&mut self, events: &[TimedEvent], _inputs: Option<AudioBus<f32>>, outputs: Option<AudioBus<f32>>) {
+        let mut outs = match outputs {
+            Some(outs) => outs,
+            None => {
+                for timed in events {
+                    self.midi_message(timed.message);
                 }
+                return;
             }
+        };
+
+        let len = cmp::min(outs[0].len(), outs[1].len());
+        let mut start = 0;
+        for timed in events {
+            let offset = cmp::min(timed.offset, len);
+            if offset > start {
+                self.render(&mut outs, start, offset);
+                start = offset;
+            }
+            self.midi_message(timed.message);
         }
+        self.render(&mut outs, start, len);
     }
 
     fn note_on(&mut self, note: u8, velocity: u8) {
-        match self.voices.iter_mut().nth(self.voice_cycle as _) {
-            Some(voice) => {
-                if voice.current_note() == None {
-                    voice.note_on(note, velocity);
-                    self.voice_cycle = (self.voice_cycle + 1) % 8;
-                    return;
-                }
+        match self.play_mode() {
+            PlayMode::Poly => {
+                let index = self.allocate_voice(note);
+                self.start_note(index, note, velocity, false);
             },
-            _ => ()
-        };
-        for voice in self.voices.iter_mut() {
-            if voice.current_note() == None {
-                voice.note_on(note, velocity);
-                return;
+            mode => {
+                let held = !self.note_stack.is_empty();
+                self.note_stack.push(note, velocity);
+                self.play_mono_note(mode, held);
             }
         }
     }
 
     fn note_off(&mut self, note: u8, velocity: u8) {
-        for voice in self.voices.iter_mut() {
-            if voice.current_note() == Some(note) {
-                voice.note_off(note, velocity);
-                break;
+        match self.play_mode() {
+            PlayMode::Poly => {
+                let held = self.voices.iter()
+                    .position(|slot| slot.current_note() == Some(note) && slot.channel.is_none() && !slot.sustained);
+                if let Some(index) = held {
+                    self.release_slot(index, note, velocity);
+                }
+            },
+            mode => {
+                self.note_stack.remove(note);
+                if self.voices[0].current_note() != Some(note) {
+                    return;
+                }
+                if self.note_stack.is_empty() {
+                    self.release_slot(0, note, velocity);
+                } else {
+                    self.play_mono_note(mode, true);
+                }
             }
         }
     }
 
+    fn pitch_bend (&mut self, value: f32) {
+        self.pitch_bend = value.max(-1.0).min(1.0);
+        self.update_bend();
+    }
+
+    fn midi_message (&mut self, message: MidiMessage) {
+        if let MidiMessage::ControlChange { channel, controller, value } = message {
+            if let Some(rpn) = self.mpe.control_change(channel, controller, value) {
+                self.registered_parameter(rpn);
+            }
+        }
+
+        let member_channel = message.channel()
+            .map_or(false, |channel| self.mpe.is_member(channel));
+        if member_channel && self.mpe_enabled() {
+            self.mpe_message(message);
+        } else {
+            dispatch_midi(self, message);
+        }
+    }
+
+    fn poly_pressure (&mut self, note: u8, pressure: u8) {
+        let pressure = pressure as f32 / 127.0;
+        let channel_pressure = self.channel_pressure;
+        for slot in self.voices.iter_mut() {
+            if slot.current_note() == Some(note) {
+                slot.pressure = pressure;
+                slot.voice.set_pressure(pressure.max(channel_pressure));
+            }
+        }
+    }
+
+    fn channel_pressure (&mut self, pressure: u8) {
+        self.channel_pressure = pressure as f32 / 127.0;
+        let channel_pressure = self.channel_pressure;
+        for slot in self.voices.iter_mut() {
+            slot.voice.set_pressure(slot.pressure.max(channel_pressure));
+        }
+    }
+
+    fn control_change (&mut self, controller: u8, value: u8) {
+        match controller {
+            CC_MOD_WHEEL => {
+                self.mod_wheel = value as f32 / 127.0;
+                self.update_voice_params();
+            },
+            CC_SUSTAIN => self.set_sustain(value >= 64),
+            CC_SOSTENUTO => self.set_sostenuto(value >= 64),
+            _ => ()
+        }
+    }
+
     fn set_sample_rate (&mut self, sample_rate: f32) {
         self.sample_rate = sample_rate;
-        let bag = &self.params;
-        for voice in self.voices.iter_mut() {
-            voice.init(bag, self.sample_rate);
+        let bag = &self.voice_params;
+        for slot in self.voices.iter_mut() {
+            slot.voice.init(bag, self.sample_rate);
+        }
+        self.update_glide_time();
+        self.update_smoothing_time();
+        self.effects.set_sample_rate(sample_rate);
+    }
+
+    fn set_tempo (&mut self, bpm: f32) {
+        for slot in self.voices.iter_mut() {
+            slot.voice.set_tempo(bpm);
         }
+        self.effects.set_tempo(bpm);
     }
 
     fn get_num_parameters (&self) -> i32
     {
-        V::ParamsEnum::NUM_ITEMS as i32
+        (V::ParamsEnum::NUM_ITEMS + SynthParams::NUM_ITEMS + self.effects.get_num_parameters()) as i32
     }
 
     fn get_parameter (&self, index: i32) -> f32 {
+        if let Some(param) = self.effect_param(index) {
+            return self.effects.get_parameter(param);
+        }
+        if let Some(param) = self.synth_param(index) {
+            return self.synth_params.get(param);
+        }
+
         let param = V::ParamsEnum::from_index(index as u32);
         self.params.get(param)
     }
 }
+
+#[cfg(test)]
+mod test_voice {
+    use frame::Frame;
+    use mod_matrix::ModOffsets;
+    use params_bag::ParamsBag;
+    use smoother::Smoother;
+    use voice::Voice;
+    use IndexedEnum;
+
+    #[derive(Debug, Clone, Copy, PartialEq, IndexedEnum)]
+    pub enum TestParams {
+        Level,
+        MasterLevel,
+    }
+
+    define_params_bag!(TestParamsBag, TestParams, [
+        1.0, // level
+        1.0, // master level
+    ]);
+
+    /// Plays its velocity times its level while the key is down and stops at once on release,
+    /// keeping what the synth last told it.
+    #[derive(Default)]
+    pub struct TestVoice {
+        pub note: Option<u8>,
+        pub velocity: f32,
+        pub frequency: f32,
+        pub pressure: f32,
+        pub timbre: f32,
+        pub smoothing: f32,
+        pub level: f32,
+        modulation: ModOffsets<TestParams>,
+    }
+
+    impl Voice for TestVoice {
+        type ParamsEnum = TestParams;
+        type Bag = TestParamsBag;
+        type PostParam = Smoother;
+        type Depth = f32;
+
+        fn current_note (&self) -> Option<u8> { self.note }
+
+        fn note_on (&mut self, note: u8, velocity: u8) {
+            self.note = Some(note);
+            self.velocity = velocity as f32 / 127.0;
+        }
+
+        fn note_off (&mut self, _note: u8, _velocity: u8) { self.note = None }
+        fn legato (&mut self, note: u8, _velocity: u8) { self.note = Some(note) }
+        fn set_frequency (&mut self, freq: f32) { self.frequency = freq }
+        fn set_pressure (&mut self, pressure: f32) { self.pressure = pressure }
+        fn set_timbre (&mut self, timbre: f32) { self.timbre = timbre }
+        fn set_smoothing (&mut self, _sample_rate: f32, time: f32) { self.smoothing = time }
+        fn set_modulation (&mut self, offsets: &ModOffsets<TestParams>) { self.modulation = offsets.clone() }
+
+        fn init_process (&mut self, params: &TestParamsBag) -> bool {
+            self.level = self.modulation.apply(TestParams::Level, params.get(TestParams::Level));
+            !self.is_finished()
+        }
+
+        fn process_sample (&mut self, _timestep: f32) -> Frame {
+            let value = self.velocity * self.level;
+            Frame { l: value, r: value }
+        }
+
+        fn is_finished (&self) -> bool { self.note.is_none() }
+        fn level (&self) -> f32 { if self.is_finished() { 0.0 } else { self.velocity } }
+        fn reset (&mut self) { self.note = None }
+
+        fn prepare_post (params: &TestParamsBag, master: &mut Smoother) {
+            master.set_target(params.get(TestParams::MasterLevel));
+        }
+
+        fn process_post (master: &mut Smoother, frame: Frame) -> Frame { frame * master.process() }
+        fn set_post_smoothing (master: &mut Smoother, sample_rate: f32, time: f32) { master.set_time(sample_rate, time) }
+        fn jump_post (master: &mut Smoother) { master.jump() }
+
+        fn update_param (&mut self, bag: &TestParamsBag, param: TestParams, _sample_rate: f32) {
+            if param == TestParams::Level {
+                self.level = self.modulation.apply(TestParams::Level, bag.get(TestParams::Level));
+            }
+        }
+
+        fn is_mod_destination (param: TestParams) -> bool {
+            param != TestParams::MasterLevel
+        }
+    }
+}
+
+#[cfg(test)]
+type TestSynth = PolySynth<test_voice::TestVoice>;
+
+#[cfg(test)]
+fn test_synth () -> TestSynth {
+    let mut synth = TestSynth::default();
+    synth.set_sample_rate(1000.0);
+    synth
+}
+
+#[cfg(test)]
+fn set_synth_param (synth: &mut TestSynth, param: SynthParams, value: f32) {
+    let index = test_voice::TestParams::NUM_ITEMS + param.to_index();
+    synth.set_parameter(index as i32, value);
+}
+
+/// Control value selecting `item` of an enum parameter.
+#[cfg(test)]
+fn control<E: IndexedEnum> (item: E) -> f32 {
+    (item.to_index() as f32 + 0.5) / E::NUM_ITEMS as f32
+}
+
+#[cfg(test)]
+fn timed (offset: usize, message: MidiMessage) -> TimedEvent {
+    TimedEvent { offset: offset, message: message }
+}
+
+#[cfg(test)]
+fn note_on (offset: usize, note: u8, velocity: u8) -> TimedEvent {
+    timed(offset, MidiMessage::NoteOn { channel: 0, note: note, velocity: velocity })
+}
+
+#[cfg(test)]
+fn note_off (offset: usize, note: u8) -> TimedEvent {
+    timed(offset, MidiMessage::NoteOff { channel: 0, note: note, velocity: 0 })
+}
+
+#[cfg(test)]
+fn control_change (offset: usize, channel: u8, controller: u8, value: u8) -> TimedEvent {
+    timed(offset, MidiMessage::ControlChange { channel: channel, controller: controller, value: value })
+}
+
+/// Runs `events` over `len` samples, returning the left channel.
+#[cfg(test)]
+fn render_events (synth: &mut TestSynth, events: &[TimedEvent], len: usize) -> Vec<f32> {
+    let mut left = vec![0.0; len];
+    let mut right = vec![0.0; len];
+    synth.run(events, None, Some([&mut left, &mut right]));
+    left
+}
+
+#[cfg(test)]
+fn voice_on (synth: &TestSynth, note: u8) -> Option<&test_voice::TestVoice> {
+    synth.voices().find(|voice| voice.note == Some(note))
+}
+
+#[cfg(test)]
+fn sounding_notes (synth: &TestSynth) -> Vec<u8> {
+    let mut notes: Vec<u8> = synth.voices().filter_map(|voice| voice.note).collect();
+    notes.sort();
+    notes
+}
+
+#[test]
+fn test_matrix_modulates_each_voice () {
+    use self::test_voice::TestParams;
+
+    let mut synth = test_synth();
+    set_synth_param(&mut synth, SynthParams::Mod1Source, control(ModSource::Velocity));
+    set_synth_param(&mut synth, SynthParams::Mod1Destination, 1.0);
+    // velocity turns the level down by half its value
+    set_synth_param(&mut synth, SynthParams::Mod1Amount, 0.25);
+    let out = render_events(&mut synth, &[note_on(0, 60, 127), note_on(0, 62, 64)], 64);
+
+    let soft = 64.0 / 127.0;
+    assert_eq!(voice_on(&synth, 60).unwrap().level, 0.5);
+    assert!((voice_on(&synth, 62).unwrap().level - (1.0 - 0.5 * soft)).abs() < 1e-6);
+    assert!((out[63] - (0.5 + soft * (1.0 - 0.5 * soft))).abs() < 1e-6);
+    // the shared parameters stay as set
+    assert_eq!(synth.params.get(TestParams::Level), 1.0);
+    assert_eq!(synth.voice_params.get(TestParams::Level), 1.0);
+}
+
+#[test]
+fn test_matrix_skips_output_destinations () {
+    use self::test_voice::TestParams;
+
+    let synth = test_synth();
+    assert_eq!(synth.mod_destination(0.0), None);
+    assert_eq!(synth.mod_destination(1.0), Some(TestParams::Level));
+    for step in 0..101 {
+        assert_ne!(synth.mod_destination(step as f32 / 100.0), Some(TestParams::MasterLevel));
+    }
+}
+
+#[test]
+fn test_voice_steal_policies () {
+    let stolen = |policy: VoiceSteal, note: u8| {
+        let mut synth = test_synth();
+        set_synth_param(&mut synth, SynthParams::Polyphony, 3.0 / 31.0);
+        set_synth_param(&mut synth, SynthParams::VoiceSteal, control(policy));
+        // the oldest, the quietest, the lowest and the highest note
+        let events = [
+            note_on(0, 62, 100),
+            note_on(1, 64, 20),
+            note_on(2, 60, 100),
+            note_on(3, 67, 100),
+            note_on(4, note, 100),
+        ];
+        render_events(&mut synth, &events, 20);
+        let lost: Vec<u8> = [60, 62, 64, 67].iter().cloned()
+            .filter(|&held| voice_on(&synth, held).is_none())
+            .collect();
+        let velocity = voice_on(&synth, note).map(|voice| voice.velocity);
+        (lost, velocity)
+    };
+    let taken_over = Some(100.0 / 127.0);
+    assert_eq!(stolen(VoiceSteal::Oldest, 70), (vec![62], taken_over));
+    assert_eq!(stolen(VoiceSteal::Quietest, 70), (vec![64], taken_over));
+    assert_eq!(stolen(VoiceSteal::Lowest, 70), (vec![60], taken_over));
+    assert_eq!(stolen(VoiceSteal::Highest, 70), (vec![67], taken_over));
+    assert_eq!(stolen(VoiceSteal::SameNote, 70), (vec![62], taken_over));
+    // a repeated key restarts its own voice
+    assert_eq!(stolen(VoiceSteal::SameNote, 64), (vec![], taken_over));
+}
+
+#[test]
+fn test_stolen_voice_fades_out () {
+    let mut synth = test_synth();
+    set_synth_param(&mut synth, SynthParams::Polyphony, 0.0);
+    let out = render_events(&mut synth, &[note_on(0, 60, 127), note_on(10, 62, 64)], 30);
+
+    // the old note ramps down over 5ms before the new one starts
+    assert_eq!(out[10], 1.0);
+    for i in 11..15 {
+        assert!(out[i] < out[i - 1] && out[i] > 0.0);
+    }
+    assert_eq!(out[29], 64.0 / 127.0);
+    assert!(voice_on(&synth, 60).is_none());
+    assert!(voice_on(&synth, 62).is_some());
+}
+
+#[test]
+fn test_polyphony_changes_while_playing () {
+    let mut synth = test_synth();
+    set_synth_param(&mut synth, SynthParams::Polyphony, 1.0 / 31.0);
+    render_events(&mut synth, &[note_on(0, 60, 100), note_on(1, 64, 100), note_on(2, 67, 100)], 20);
+    assert_eq!(sounding_notes(&synth), vec![64, 67]);
+
+    set_synth_param(&mut synth, SynthParams::Polyphony, 1.0);
+    render_events(&mut synth, &[note_on(0, 72, 100), note_on(1, 76, 100)], 20);
+    assert_eq!(sounding_notes(&synth), vec![64, 67, 72, 76]);
+
+    // voices past the new count are released
+    set_synth_param(&mut synth, SynthParams::Polyphony, 0.0);
+    assert_eq!(sounding_notes(&synth).len(), 1);
+    assert_eq!(synth.synth_parameter_text(SynthParams::Polyphony), "1");
+}
+
+#[test]
+fn test_mono_returns_to_held_note () {
+    let mut synth = test_synth();
+    set_synth_param(&mut synth, SynthParams::PlayMode, control(PlayMode::Mono));
+    render_events(&mut synth, &[note_on(0, 60, 100), note_on(10, 64, 50)], 20);
+    // every new key retriggers the single voice with its own velocity
+    assert_eq!(sounding_notes(&synth), vec![64]);
+    assert_eq!(voice_on(&synth, 64).unwrap().velocity, 50.0 / 127.0);
+
+    render_events(&mut synth, &[note_off(0, 64)], 20);
+    assert_eq!(sounding_notes(&synth), vec![60]);
+    assert_eq!(voice_on(&synth, 60).unwrap().velocity, 100.0 / 127.0);
+
+    render_events(&mut synth, &[note_off(0, 60)], 20);
+    assert!(sounding_notes(&synth).is_empty());
+}
+
+#[test]
+fn test_legato_glides_without_retrigger () {
+    let mut synth = test_synth();
+    set_synth_param(&mut synth, SynthParams::PlayMode, control(PlayMode::Legato));
+    // 0.5 maps to 500ms
+    set_synth_param(&mut synth, SynthParams::GlideTime, 0.5);
+    render_events(&mut synth, &[note_on(0, 60, 100)], 10);
+    // the first note has nothing to glide from
+    assert_eq!(voice_on(&synth, 60).unwrap().frequency, helpers::pitch_to_hz(60.0));
+
+    render_events(&mut synth, &[note_on(0, 64, 50)], 100);
+    let voice = voice_on(&synth, 64).unwrap();
+    assert_eq!(voice.velocity, 100.0 / 127.0);
+    assert!(voice.frequency > helpers::pitch_to_hz(60.0) && voice.frequency < helpers::pitch_to_hz(64.0));
+
+    render_events(&mut synth, &[], 1000);
+    assert_eq!(voice_on(&synth, 64).unwrap().frequency, helpers::pitch_to_hz(64.0));
+
+    // releasing the top key slides back to the one still held
+    render_events(&mut synth, &[note_off(0, 64)], 10);
+    let voice = voice_on(&synth, 60).unwrap();
+    assert_eq!(voice.velocity, 100.0 / 127.0);
+    assert!(voice.frequency < helpers::pitch_to_hz(64.0));
+}
+
+#[test]
+fn test_bend_range () {
+    let mut synth = test_synth();
+    set_synth_param(&mut synth, SynthParams::BendRange, 0.5);
+    let bend_down = timed(1, MidiMessage::PitchBend { channel: 0, value: -8192 });
+    render_events(&mut synth, &[note_on(0, 60, 100), bend_down], 10);
+    assert_eq!(voice_on(&synth, 60).unwrap().frequency, helpers::pitch_to_hz(48.0));
+
+    // the range can also be set by the controller, through its registered parameter
+    let range = [
+        control_change(0, 0, 101, 0),
+        control_change(0, 0, 100, 0),
+        control_change(0, 0, 6, 7),
+    ];
+    render_events(&mut synth, &range, 10);
+    assert_eq!(synth.synth_parameter_text(SynthParams::BendRange), "7");
+    assert_eq!(voice_on(&synth, 60).unwrap().frequency, helpers::pitch_to_hz(53.0));
+}
+
+#[test]
+fn test_mod_wheel_offsets_target () {
+    use self::test_voice::TestParams;
+
+    let mut synth = test_synth();
+    synth.set_parameter(TestParams::Level.to_index() as i32, 0.25);
+    // past the lowest third, which turns the wheel off
+    set_synth_param(&mut synth, SynthParams::ModWheelTarget, 0.5);
+    assert_eq!(synth.synth_parameter_text(SynthParams::ModWheelTarget), "Level");
+    render_events(&mut synth, &[note_on(0, 60, 100), control_change(1, 0, 1, 127)], 10);
+    assert_eq!(voice_on(&synth, 60).unwrap().level, 1.0);
+
+    // negative depth turns the target down instead
+    set_synth_param(&mut synth, SynthParams::ModWheelDepth, 0.0);
+    render_events(&mut synth, &[], 10);
+    assert_eq!(voice_on(&synth, 60).unwrap().level, 0.0);
+}
+
+#[test]
+fn test_sustain_holds_released_notes () {
+    let mut synth = test_synth();
+    let events = [
+        note_on(0, 60, 100),
+        control_change(1, 0, CC_SUSTAIN, 127),
+        note_off(2, 60),
+        note_on(3, 64, 100),
+        note_off(4, 64),
+    ];
+    render_events(&mut synth, &events, 10);
+    // keys pressed before and while the pedal is down ring on
+    assert_eq!(sounding_notes(&synth), vec![60, 64]);
+
+    render_events(&mut synth, &[control_change(0, 0, CC_SUSTAIN, 0)], 10);
+    assert!(sounding_notes(&synth).is_empty());
+
+    // switching the play mode releases everything and lifts the pedal
+    render_events(&mut synth, &[note_on(0, 60, 100), control_change(1, 0, CC_SUSTAIN, 127)], 10);
+    set_synth_param(&mut synth, SynthParams::PlayMode, control(PlayMode::Poly));
+    assert!(sounding_notes(&synth).is_empty());
+    render_events(&mut synth, &[note_on(0, 62, 100), note_off(1, 62)], 10);
+    assert!(sounding_notes(&synth).is_empty());
+}
+
+#[test]
+fn test_sostenuto_latches_held_notes () {
+    let mut synth = test_synth();
+    let events = [
+        note_on(0, 60, 100),
+        control_change(1, 0, CC_SOSTENUTO, 127),
+        note_off(2, 60),
+        note_on(3, 64, 100),
+        note_off(4, 64),
+    ];
+    render_events(&mut synth, &events, 10);
+    // only the key held when the pedal went down is kept
+    assert_eq!(sounding_notes(&synth), vec![60]);
+
+    render_events(&mut synth, &[control_change(0, 0, CC_SOSTENUTO, 0)], 10);
+    assert!(sounding_notes(&synth).is_empty());
+}
+
+#[test]
+fn test_aftertouch_reaches_voices () {
+    let poly_pressure = timed(1, MidiMessage::PolyPressure { channel: 0, note: 64, pressure: 127 });
+    let mut synth = test_synth();
+    render_events(&mut synth, &[note_on(0, 60, 100), note_on(0, 64, 100), poly_pressure], 10);
+    assert_eq!(voice_on(&synth, 60).unwrap().pressure, 0.0);
+    assert_eq!(voice_on(&synth, 64).unwrap().pressure, 1.0);
+
+    // the voices follow the stronger of channel and key pressure
+    render_events(&mut synth, &[timed(0, MidiMessage::ChannelPressure { channel: 0, pressure: 64 })], 10);
+    let channel = 64.0 / 127.0;
+    assert_eq!(voice_on(&synth, 60).unwrap().pressure, channel);
+    assert_eq!(voice_on(&synth, 64).unwrap().pressure, 1.0);
+
+    // pressure as a matrix source, turning the level down
+    set_synth_param(&mut synth, SynthParams::Mod1Source, control(ModSource::Aftertouch));
+    set_synth_param(&mut synth, SynthParams::Mod1Destination, 1.0);
+    set_synth_param(&mut synth, SynthParams::Mod1Amount, 0.0);
+    render_events(&mut synth, &[], 10);
+    assert!((voice_on(&synth, 60).unwrap().level - (1.0 - channel)).abs() < 1e-6);
+    assert_eq!(voice_on(&synth, 64).unwrap().level, 0.0);
+}
+
+#[test]
+fn test_mpe_expression_per_channel () {
+    let mut synth = test_synth();
+    set_synth_param(&mut synth, SynthParams::Mpe, 1.0);
+    let events = [
+        timed(0, MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 }),
+        timed(0, MidiMessage::NoteOn { channel: 2, note: 64, velocity: 100 }),
+        timed(1, MidiMessage::PitchBend { channel: 1, value: -8192 }),
+        timed(1, MidiMessage::ChannelPressure { channel: 2, pressure: 127 }),
+        control_change(1, 2, CC_TIMBRE, 127),
+    ];
+    render_events(&mut synth, &events, 10);
+    // member channels bend 48 semitones by default
+    let low = voice_on(&synth, 60).unwrap();
+    assert_eq!(low.frequency, helpers::pitch_to_hz(12.0));
+    assert_eq!((low.pressure, low.timbre), (0.0, 0.0));
+    let high = voice_on(&synth, 64).unwrap();
+    assert_eq!(high.frequency, helpers::pitch_to_hz(64.0));
+    assert_eq!((high.pressure, high.timbre), (1.0, 1.0));
+
+    // the manager channel bends every note by the zone's range
+    render_events(&mut synth, &[timed(0, MidiMessage::PitchBend { channel: 0, value: -8192 })], 10);
+    assert_eq!(voice_on(&synth, 60).unwrap().frequency, helpers::pitch_to_hz(10.0));
+    assert_eq!(voice_on(&synth, 64).unwrap().frequency, helpers::pitch_to_hz(62.0));
+
+    // notes played on member channels are only released from their own channel
+    render_events(&mut synth, &[note_off(0, 60)], 10);
+    assert_eq!(sounding_notes(&synth), vec![60, 64]);
+    render_events(&mut synth, &[timed(0, MidiMessage::NoteOff { channel: 1, note: 60, velocity: 0 })], 10);
+    assert_eq!(sounding_notes(&synth), vec![64]);
+}
+
+#[test]
+fn test_mpe_zone_from_controller () {
+    let mut synth = test_synth();
+    // configuration message for a lower zone of three member channels
+    let zone = [
+        control_change(0, 0, 101, 0),
+        control_change(0, 0, 100, 6),
+        control_change(0, 0, 6, 3),
+    ];
+    render_events(&mut synth, &zone, 10);
+    assert_eq!(synth.synth_parameter_text(SynthParams::Mpe), "true");
+
+    let events = [
+        timed(0, MidiMessage::NoteOn { channel: 3, note: 60, velocity: 100 }),
+        timed(0, MidiMessage::NoteOn { channel: 4, note: 64, velocity: 100 }),
+        timed(1, MidiMessage::PitchBend { channel: 3, value: -8192 }),
+        timed(1, MidiMessage::PitchBend { channel: 4, value: -8192 }),
+    ];
+    render_events(&mut synth, &events, 10);
+    // channels outside the zone bend every note, by the regular range
+    assert_eq!(voice_on(&synth, 60).unwrap().frequency, helpers::pitch_to_hz(10.0));
+    assert_eq!(voice_on(&synth, 64).unwrap().frequency, helpers::pitch_to_hz(62.0));
+}
+
+#[test]
+fn test_master_level_ramps () {
+    use self::test_voice::TestParams;
+
+    let mut synth = test_synth();
+    // 0.5 maps to 50ms, 50 samples
+    set_synth_param(&mut synth, SynthParams::SmoothingTime, 0.5);
+    let out = render_events(&mut synth, &[note_on(0, 60, 127)], 10);
+    // nothing to ramp from after silence
+    assert_eq!(out[0], 1.0);
+    assert!(synth.voices().all(|voice| (voice.smoothing - 0.05).abs() < 1e-6));
+
+    synth.set_parameter(TestParams::MasterLevel.to_index() as i32, 0.0);
+    let out = render_events(&mut synth, &[], 100);
+    assert!(out[0] > 0.95 && out[0] < 1.0);
+    assert!((out[24] - 0.5).abs() < 0.05);
+    assert!(out.windows(2).all(|pair| pair[1] <= pair[0]));
+    assert_eq!(out[60], 0.0);
+}
@@ -0,0 +1,272 @@
+use effect::Effect;
+use frame::Frame;
+use params_bag::ParamsBag;
+use IndexedEnum;
+
+// Freeverb tunings, in samples at 44.1kHz
+const COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD: usize = 23;
+const TUNING_RATE: f32 = 44100.0;
+const MIN_SIZE: f32 = 0.3;
+const MAX_SIZE: f32 = 1.5;
+const MAX_PRE_DELAY: f32 = 0.2;
+const INPUT_GAIN: f32 = 0.015;
+const ALLPASS_FEEDBACK: f32 = 0.5;
+
+#[derive(Debug, Clone, Copy, IndexedEnum)]
+pub enum ReverbParams {
+    ReverbSize,
+    ReverbDecay,
+    ReverbDamping,
+    ReverbPreDelay,
+    ReverbWidth,
+    ReverbMix,
+}
+
+define_params_bag!(ReverbParamsBag, ReverbParams, [
+    0.6, // size
+    0.5, // decay
+    0.5, // damping
+    0.1, // pre-delay, 20ms
+    1.0, // width
+    0.0, // mix, off
+]);
+
+fn size_scale (value: f32) -> f32 {
+    MIN_SIZE + value * (MAX_SIZE - MIN_SIZE)
+}
+
+fn pre_delay_time (value: f32) -> f32 {
+    value * MAX_PRE_DELAY
+}
+
+/// Delay line whose length can shrink below the allocated buffer.
+#[derive(Default)]
+struct DelayLine {
+    buffer: Vec<f32>,
+    len: usize,
+    index: usize,
+}
+
+impl DelayLine {
+    fn allocate (&mut self, capacity: usize) {
+        self.buffer = vec![0.0; capacity.max(1)];
+        self.len = self.buffer.len();
+        self.index = 0;
+    }
+
+    fn set_len (&mut self, len: usize) {
+        self.len = len.max(1).min(self.buffer.len());
+        if self.index >= self.len {
+            self.index = 0;
+        }
+    }
+
+    fn clear (&mut self) {
+        for sample in self.buffer.iter_mut() {
+            *sample = 0.0;
+        }
+    }
+
+    /// Returns the sample written `len` samples ago and stores `input` in its place.
+    #[inline]
+    fn swap (&mut self, input: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.buffer[self.index] = input;
+        self.index += 1;
+        if self.index >= self.len {
+            self.index = 0;
+        }
+        output
+    }
+}
+
+#[derive(Default)]
+struct Comb {
+    line: DelayLine,
+    filter: f32,
+}
+
+impl Comb {
+    #[inline]
+    fn process (&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.line.buffer[self.line.index];
+        self.filter = output * (1.0 - damping) + self.filter * damping;
+        self.line.swap(input + self.filter * feedback);
+        output
+    }
+}
+
+#[inline]
+fn process_allpass (line: &mut DelayLine, input: f32) -> f32 {
+    let delayed = line.buffer[line.index];
+    line.swap(input + delayed * ALLPASS_FEEDBACK);
+    delayed - input
+}
+
+/// Freeverb-style stereo reverb: parallel lowpass combs into series allpasses per channel,
+/// the right channel tuned slightly longer to decorrelate the sides.
+#[derive(Default)]
+pub struct Reverb {
+    params: ReverbParamsBag,
+    sample_rate: f32,
+    pre_delay: [DelayLine; 2],
+    combs: [[Comb; 8]; 2],
+    allpasses: [[DelayLine; 4]; 2],
+    feedback: f32,
+    damping: f32,
+}
+
+impl Reverb {
+    fn update (&mut self) {
+        let rate_scale = self.sample_rate / TUNING_RATE;
+        let size = size_scale(self.params.get(ReverbParams::ReverbSize)) * rate_scale;
+        for (channel, combs) in self.combs.iter_mut().enumerate() {
+            for (comb, tuning) in combs.iter_mut().zip(COMB_TUNINGS.iter()) {
+                comb.line.set_len(((tuning + channel * STEREO_SPREAD) as f32 * size) as usize);
+            }
+        }
+        let pre_delay = pre_delay_time(self.params.get(ReverbParams::ReverbPreDelay)) * self.sample_rate;
+        for line in self.pre_delay.iter_mut() {
+            line.set_len(pre_delay as usize + 1);
+        }
+
+        self.feedback = 0.7 + self.params.get(ReverbParams::ReverbDecay) * 0.28;
+        self.damping = self.params.get(ReverbParams::ReverbDamping) * 0.4;
+    }
+}
+
+impl Effect for Reverb {
+    fn get_num_parameters (&self) -> u32 {
+        ReverbParams::NUM_ITEMS
+    }
+
+    fn get_parameter (&self, index: u32) -> f32 {
+        self.params.get(ReverbParams::from_index(index))
+    }
+
+    fn set_parameter (&mut self, index: u32, value: f32) {
+        self.params.set(ReverbParams::from_index(index), value);
+        self.update();
+    }
+
+    fn get_parameter_name (&self, index: u32) -> String {
+        format!("{:?}", ReverbParams::from_index(index))
+    }
+
+    fn get_parameter_label (&self, index: u32) -> String {
+        match ReverbParams::from_index(index) {
+            ReverbParams::ReverbPreDelay => "ms".to_string(),
+            ReverbParams::ReverbSize => "".to_string(),
+            _ => "%".to_string(),
+        }
+    }
+
+    fn get_parameter_text (&self, index: u32) -> String {
+        let param = ReverbParams::from_index(index);
+        let value = self.params.get(param);
+        match param {
+            ReverbParams::ReverbSize => format!("{:.2}", size_scale(value)),
+            ReverbParams::ReverbPreDelay => format!("{:.0}", pre_delay_time(value) * 1000.0),
+            _ => format!("{:.0}", value * 100.0),
+        }
+    }
+
+    fn set_sample_rate (&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        let rate_scale = sample_rate / TUNING_RATE;
+        for (channel, combs) in self.combs.iter_mut().enumerate() {
+            for (comb, tuning) in combs.iter_mut().zip(COMB_TUNINGS.iter()) {
+                comb.line.allocate(((tuning + channel * STEREO_SPREAD) as f32 * MAX_SIZE * rate_scale) as usize + 1);
+                comb.filter = 0.0;
+            }
+        }
+        for (channel, allpasses) in self.allpasses.iter_mut().enumerate() {
+            for (line, tuning) in allpasses.iter_mut().zip(ALLPASS_TUNINGS.iter()) {
+                line.allocate(((tuning + channel * STEREO_SPREAD) as f32 * rate_scale) as usize);
+            }
+        }
+        for line in self.pre_delay.iter_mut() {
+            line.allocate((MAX_PRE_DELAY * sample_rate) as usize + 1);
+        }
+        self.update();
+    }
+
+    fn is_active (&self) -> bool {
+        self.params.get(ReverbParams::ReverbMix) > 0.0
+    }
+
+    fn tail_gap (&self) -> usize {
+        // the longest way through: pre-delay, the longest comb, then every allpass
+        let comb = self.combs[1].iter().map(|comb| comb.line.len).max().unwrap_or(0);
+        let allpasses: usize = self.allpasses[1].iter().map(|line| line.len).sum();
+        self.pre_delay[1].len + comb + allpasses
+    }
+
+    fn reset (&mut self) {
+        for combs in self.combs.iter_mut() {
+            for comb in combs.iter_mut() {
+                comb.line.clear();
+                comb.filter = 0.0;
+            }
+        }
+        for line in self.allpasses.iter_mut().flat_map(|lines| lines.iter_mut()).chain(self.pre_delay.iter_mut()) {
+            line.clear();
+        }
+    }
+
+    #[inline]
+    fn process (&mut self, frame: Frame) -> Frame {
+        if self.pre_delay[0].buffer.is_empty() {
+            return frame;
+        }
+
+        let input = [
+            self.pre_delay[0].swap(frame.l) * INPUT_GAIN,
+            self.pre_delay[1].swap(frame.r) * INPUT_GAIN,
+        ];
+        let mut wet = [0.0; 2];
+        for channel in 0..2 {
+            // both channels feed every comb, the tunings tell them apart
+            let mono = input[0] + input[1];
+            let mut sum = 0.0;
+            for comb in self.combs[channel].iter_mut() {
+                sum += comb.process(mono, self.feedback, self.damping);
+            }
+            for line in self.allpasses[channel].iter_mut() {
+                sum = process_allpass(line, sum);
+            }
+            wet[channel] = sum;
+        }
+
+        let width = self.params.get(ReverbParams::ReverbWidth);
+        let mix = self.params.get(ReverbParams::ReverbMix);
+        let wet1 = mix * (width * 0.5 + 0.5);
+        let wet2 = mix * (1.0 - width) * 0.5;
+        Frame {
+            l: frame.l * (1.0 - mix) + wet[0] * wet1 + wet[1] * wet2,
+            r: frame.r * (1.0 - mix) + wet[1] * wet1 + wet[0] * wet2,
+        }
+    }
+}
+
+#[test]
+fn test_reverb_tail_decays () {
+    let mut reverb = Reverb::default();
+    reverb.set_sample_rate(44100.0);
+    reverb.set_parameter(ReverbParams::ReverbMix.to_index(), 1.0);
+    reverb.set_parameter(ReverbParams::ReverbPreDelay.to_index(), 0.0);
+
+    let energy = |reverb: &mut Reverb, samples: usize, input: f32| {
+        (0..samples).map(|_| {
+            let out = reverb.process(Frame { l: input, r: input });
+            out.l * out.l + out.r * out.r
+        }).sum::<f32>()
+    };
+    energy(&mut reverb, 4410, 0.5);
+    let early = energy(&mut reverb, 44100, 0.0);
+    let late = energy(&mut reverb, 44100, 0.0);
+    assert!(early > 0.0);
+    assert!(late < early * 0.1);
+}
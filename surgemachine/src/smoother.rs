@@ -0,0 +1,53 @@
+/// Linear ramp towards the last set target, so that parameter changes arriving
+/// once per block don't step the signal.
+#[derive(Default)]
+pub struct Smoother {
+    value: f32,
+    target: f32,
+    step: f32,
+    ramp_samples: u32,
+    remaining: u32,
+}
+
+impl Smoother {
+    pub fn set_time (&mut self, sample_rate: f32, time: f32) {
+        self.ramp_samples = (time * sample_rate).max(0.0) as u32;
+    }
+
+    pub fn set_target (&mut self, target: f32) {
+        if target == self.target {
+            return;
+        }
+        self.target = target;
+        if self.ramp_samples == 0 {
+            self.jump();
+        } else {
+            self.remaining = self.ramp_samples;
+            self.step = (self.target - self.value) / self.ramp_samples as f32;
+        }
+    }
+
+    pub fn jump (&mut self) {
+        self.value = self.target;
+        self.remaining = 0;
+    }
+
+    #[inline]
+    pub fn is_ramping (&self) -> bool {
+        self.remaining > 0
+    }
+
+    #[inline]
+    pub fn process (&mut self) -> f32 {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.value = if self.remaining == 0 { self.target } else { self.value + self.step };
+        }
+        self.value
+    }
+
+    #[inline]
+    pub fn get_value (&self) -> f32 {
+        self.value
+    }
+}
@@ -0,0 +1,103 @@
+use std::str;
+
+// "SMST", opens every state chunk
+const MAGIC: u32 = 0x5453_4D53;
+
+/// Little endian serialization used for device state chunks.
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn write_u8 (&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_u32 (&mut self, value: u32) {
+        for i in 0..4 {
+            self.data.push((value >> (i * 8)) as u8);
+        }
+    }
+
+    pub fn write_f32 (&mut self, value: f32) {
+        self.write_u32(value.to_bits());
+    }
+
+    pub fn write_bytes (&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn write_str (&mut self, text: &str) {
+        self.write_bytes(text.as_bytes());
+    }
+
+    /// Marks the chunk as written in `version` of its format.
+    pub fn write_header (&mut self, version: u32) {
+        self.write_u32(MAGIC);
+        self.write_u32(version);
+    }
+
+    pub fn into_inner (self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new (data: &'a [u8]) -> Self {
+        Self { data: data }
+    }
+
+    fn take (&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Some(head)
+    }
+
+    pub fn read_u8 (&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    pub fn read_u32 (&mut self) -> Option<u32> {
+        self.take(4).map(|bytes| {
+            bytes.iter().enumerate()
+                .fold(0, |acc, (i, &byte)| acc | (byte as u32) << (i * 8))
+        })
+    }
+
+    pub fn read_f32 (&mut self) -> Option<f32> {
+        self.read_u32().map(f32::from_bits)
+    }
+
+    pub fn read_bytes (&mut self) -> Option<&'a [u8]> {
+        match self.read_u32() {
+            Some(len) => self.take(len as usize),
+            None => None,
+        }
+    }
+
+    pub fn read_str (&mut self) -> Option<&'a str> {
+        self.read_bytes().and_then(|bytes| str::from_utf8(bytes).ok())
+    }
+
+    /// Format version of a chunk opened by `write_header`. Data without the header gives `None`
+    /// and is left unread.
+    pub fn read_header (&mut self) -> Option<u32> {
+        let mut header = StateReader::new(self.data);
+        match (header.read_u32(), header.read_u32()) {
+            (Some(MAGIC), Some(version)) => {
+                self.data = header.data;
+                Some(version)
+            },
+            _ => None,
+        }
+    }
+}
@@ -1,12 +1,17 @@
 use frame::Frame;
 use params_bag::ParamsBag;
+use mod_matrix::{ModOffsets, ModSource};
+use effect::EffectChain;
+use std::fmt::Debug;
 
 pub trait Voice {
-    type ParamsEnum: ::IndexedEnum;
+    type ParamsEnum: ::IndexedEnum + Copy + Debug;
     type Bag: ParamsBag<Self::ParamsEnum>;
-    type PostParam;
+    type PostParam: Default;
     type Depth;
 
+    const POLYPHONY: usize = 8;
+
     fn init(&mut self, bag: &Self::Bag, sample_rate: f32) {
         bag.for_each(&mut |param, _| {
             self.update_param(bag, param, sample_rate);
@@ -16,10 +21,37 @@ pub trait Voice {
     fn current_note (&self) -> Option<u8>;
     fn note_on(&mut self, note: u8, _velocity: u8);
     fn note_off(&mut self, note: u8, _velocity: u8);
+    /// Moves a held note to another key without retriggering it.
+    fn legato(&mut self, note: u8, _velocity: u8);
+    fn set_frequency(&mut self, freq: f32);
+    /// Aftertouch applied to the voice, from 0.0 to 1.0.
+    fn set_pressure(&mut self, _pressure: f32) {}
+    /// MPE timbre (CC74) from -1.0 to 1.0, 0.0 is neutral.
+    fn set_timbre(&mut self, _timbre: f32) {}
+    /// Ramp time in seconds for continuous parameters changed during a note.
+    fn set_smoothing(&mut self, _sample_rate: f32, _time: f32) {}
+    /// Seeds the voice's noise sources, so renders can be reproduced.
+    fn seed(&mut self, _seed: u32) {}
+    fn set_tempo(&mut self, _bpm: f32) {}
+    /// Current value of the voice's own modulation sources, envelopes and LFOs.
+    fn mod_source(&self, _source: ModSource) -> f32 { 0.0 }
+    /// Matrix offsets for the coming block, added to the working values of their destinations
+    /// after smoothing. Destinations only read in `update_param` are updated right after.
+    fn set_modulation(&mut self, _offsets: &ModOffsets<Self::ParamsEnum>) {}
     fn init_process(&mut self, &Self::Bag) -> bool { true }
     fn process_sample(&mut self, timestep: f32) -> Frame<Self::Depth>;
     fn is_finished (&self) -> bool;
-    fn prepare_post (&Self::Bag) -> Self::PostParam;
-    fn process_post (&Self::PostParam, f: Frame<Self::Depth>) -> Frame<Self::Depth> { f }
+    fn level (&self) -> f32;
+    fn reset (&mut self);
+    fn prepare_post (&Self::Bag, &mut Self::PostParam);
+    fn process_post (&mut Self::PostParam, f: Frame<Self::Depth>) -> Frame<Self::Depth> { f }
+    fn set_post_smoothing (&mut Self::PostParam, _sample_rate: f32, _time: f32) {}
+    /// Skips post ramps in progress, called when sound starts after silence.
+    fn jump_post (&mut Self::PostParam) {}
+    /// Effect units run on the summed output, after `process_post`.
+    fn create_effects () -> EffectChain { EffectChain::default() }
     fn update_param (&mut self, &Self::Bag, Self::ParamsEnum, f32) {}
+    /// Whether the modulation matrix can route to `param`. Parameters of the summed output
+    /// and those setting how the note responds to its velocity have nothing to modulate per voice.
+    fn is_mod_destination (_param: Self::ParamsEnum) -> bool { true }
 }
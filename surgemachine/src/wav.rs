@@ -0,0 +1,130 @@
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WavError {
+    NotWave,
+    MissingFormat,
+    MissingData,
+    UnsupportedFormat { format: u16, bits: u16 },
+}
+
+/// Decoded WAV file, mixed down to mono.
+pub struct Wav {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    /// Cycle length stored in the `clm ` chunk by wavetable editors, if present.
+    pub cycle_len: Option<usize>,
+}
+
+struct Format {
+    format: u16,
+    channels: u16,
+    sample_rate: u32,
+    bits: u16,
+}
+
+fn u16_at (data: &[u8], offset: usize) -> u16 {
+    data[offset] as u16 | (data[offset + 1] as u16) << 8
+}
+
+fn u32_at (data: &[u8], offset: usize) -> u32 {
+    u16_at(data, offset) as u32 | (u16_at(data, offset + 2) as u32) << 16
+}
+
+fn parse_format (chunk: &[u8]) -> Option<Format> {
+    if chunk.len() < 16 {
+        return None;
+    }
+    let mut format = u16_at(chunk, 0);
+    if format == FORMAT_EXTENSIBLE && chunk.len() >= 26 {
+        // the sub format GUID starts with the actual format tag
+        format = u16_at(chunk, 24);
+    }
+    Some(Format {
+        format: format,
+        channels: u16_at(chunk, 2),
+        sample_rate: u32_at(chunk, 4),
+        bits: u16_at(chunk, 14),
+    })
+}
+
+/// Reads the cycle length from a `clm ` chunk, which starts with `<!>` followed by the length.
+fn parse_cycle_len (chunk: &[u8]) -> Option<usize> {
+    if !chunk.starts_with(b"<!>") {
+        return None;
+    }
+    let digits: String = chunk[3..].iter()
+        .take_while(|&&byte| byte >= b'0' && byte <= b'9')
+        .map(|&byte| byte as char)
+        .collect();
+    digits.parse().ok().and_then(|len| if len > 0 { Some(len) } else { None })
+}
+
+fn decode_sample (format: &Format, bytes: &[u8]) -> Option<f32> {
+    match (format.format, format.bits) {
+        (FORMAT_PCM, 8) => Some((bytes[0] as f32 - 128.0) / 128.0),
+        (FORMAT_PCM, 16) => Some(u16_at(bytes, 0) as i16 as f32 / 32768.0),
+        (FORMAT_PCM, 24) => {
+            let value = (bytes[0] as i32) << 8 | (bytes[1] as i32) << 16 | (bytes[2] as i32) << 24;
+            Some((value >> 8) as f32 / 8388608.0)
+        },
+        (FORMAT_PCM, 32) => Some(u32_at(bytes, 0) as i32 as f32 / 2147483648.0),
+        (FORMAT_FLOAT, 32) => Some(f32::from_bits(u32_at(bytes, 0))),
+        _ => None,
+    }
+}
+
+pub fn read_wav (data: &[u8]) -> Result<Wav, WavError> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(WavError::NotWave);
+    }
+
+    let mut format = None;
+    let mut samples = None;
+    let mut cycle_len = None;
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let id = &data[offset..offset + 4];
+        let len = u32_at(data, offset + 4) as usize;
+        let start = offset + 8;
+        let end = if len > data.len() - start { data.len() } else { start + len };
+        let chunk = &data[start..end];
+        match id {
+            b"fmt " => format = parse_format(chunk),
+            b"data" => samples = Some(chunk),
+            b"clm " => cycle_len = parse_cycle_len(chunk),
+            _ => (),
+        }
+        // chunks are padded to an even length
+        offset = end + (len & 1);
+    }
+
+    let format = format.ok_or(WavError::MissingFormat)?;
+    let samples = samples.ok_or(WavError::MissingData)?;
+    let unsupported = WavError::UnsupportedFormat { format: format.format, bits: format.bits };
+    let sample_bytes = (format.bits / 8) as usize;
+    if format.channels == 0 || sample_bytes == 0 {
+        return Err(unsupported);
+    }
+
+    let channels = format.channels as usize;
+    let mut mono = Vec::with_capacity(samples.len() / (sample_bytes * channels));
+    for frame in samples.chunks(sample_bytes * channels) {
+        if frame.len() < sample_bytes * channels {
+            break;
+        }
+        let mut sum = 0.0;
+        for sample in frame.chunks(sample_bytes) {
+            sum += decode_sample(&format, sample).ok_or(unsupported)?;
+        }
+        mono.push(sum / channels as f32);
+    }
+
+    Ok(Wav {
+        samples: mono,
+        sample_rate: format.sample_rate,
+        cycle_len: cycle_len,
+    })
+}
@@ -1,32 +1,134 @@
 use IndexedEnum;
-use rand;
+use noise::NoiseColor;
 
 pub struct Sine;
 pub struct Saw;
 pub struct Square;
 pub struct SawExp2;
-pub struct Random;
+pub struct BlepSaw;
+pub struct BlepSquare;
+
+/// Band-limited pulse, `width` is the part of the cycle spent low.
+pub struct Pulse {
+    pub width: f32,
+}
+
+/// Triangle at `morph` 0.0, turning into a falling saw at 1.0.
+pub struct TriSaw {
+    pub morph: f32,
+}
+
+pub struct SawExp {
+    pub steepness: f32,
+}
 
 #[derive(Default)]
 pub struct SinSq {
     pub square_mix: f32,
 }
 
-#[derive(Debug, Copy, Clone, IndexedEnum)]
+/// Waveforms are only ever appended, stored selections index into this list.
+#[derive(Debug, Copy, Clone, PartialEq, IndexedEnum)]
 pub enum Dynamic {
     Sine,
     Saw,
     Square,
     SawExp2,
-    Random,
+    WhiteNoise,
+    BlepSaw,
+    BlepSquare,
+    Pulse,
+    TriSaw,
+    SawExp,
+    PinkNoise,
+    BrownNoise,
+    SampleHold,
 }
 
 impl Default for Dynamic {
     fn default() -> Dynamic { Dynamic::Sine }
 }
 
+/// Waveforms an oscillator's waveform control spans, the first ones of `Dynamic`.
+/// Its range can't grow without moving every stored selection.
+pub const CLASSIC_WAVES: u32 = 5;
+
+/// Waveforms added after the classic ones, picked by a control of their own.
+/// `Classic` leaves the choice to the waveform control.
+#[derive(Debug, Copy, Clone, PartialEq, IndexedEnum)]
+pub enum WaveVariant {
+    Classic,
+    BlepSaw,
+    BlepSquare,
+    Pulse,
+    TriSaw,
+    SawExp,
+    PinkNoise,
+    BrownNoise,
+    SampleHold,
+}
+
+impl WaveVariant {
+    fn wave(&self) -> Option<Dynamic> {
+        match *self {
+            WaveVariant::Classic => None,
+            WaveVariant::BlepSaw => Some(Dynamic::BlepSaw),
+            WaveVariant::BlepSquare => Some(Dynamic::BlepSquare),
+            WaveVariant::Pulse => Some(Dynamic::Pulse),
+            WaveVariant::TriSaw => Some(Dynamic::TriSaw),
+            WaveVariant::SawExp => Some(Dynamic::SawExp),
+            WaveVariant::PinkNoise => Some(Dynamic::PinkNoise),
+            WaveVariant::BrownNoise => Some(Dynamic::BrownNoise),
+            WaveVariant::SampleHold => Some(Dynamic::SampleHold),
+        }
+    }
+}
+
+// pulse at 50%, halfway to saw and the steepness of `SawExp2`
+const DEFAULT_SHAPE: f32 = 0.5;
+
+impl Dynamic {
+    /// Classic waveform selected by a 0.0 to 1.0 control.
+    pub fn from_classic_param(value: f32) -> Dynamic {
+        let index = (value * CLASSIC_WAVES as f32).floor().max(0.0) as u32;
+        Dynamic::from_index(index.min(CLASSIC_WAVES - 1))
+    }
+
+    /// Waveform picked by an oscillator's waveform and variant controls.
+    pub fn from_controls(waveform: f32, variant: f32) -> Dynamic {
+        WaveVariant::from_param(variant).wave()
+            .unwrap_or_else(|| Dynamic::from_classic_param(waveform))
+    }
+
+    /// Value with the shape control applied, from 0.0 to 1.0. Waveforms without
+    /// a shape control ignore it.
+    #[inline]
+    pub fn shaped_value(&self, phase: f32, increment: f32, shape: f32) -> f32 {
+        let shape = shape.max(0.0).min(1.0);
+        match *self {
+            Dynamic::Pulse => Pulse { width: 0.02 + shape * 0.96 }.value_with_increment(phase, increment),
+            Dynamic::TriSaw => TriSaw { morph: shape }.value_at_phase(phase),
+            Dynamic::SawExp => SawExp { steepness: shape * 4.0 }.value_at_phase(phase),
+            _ => self.value_with_increment(phase, increment),
+        }
+    }
+}
+
 pub trait Waveform {
     fn value_at_phase(&self, phase: f32) -> f32;
+
+    /// Value for an oscillator advancing by `increment` of a cycle per sample,
+    /// band-limited waveforms use it to smooth their discontinuities.
+    #[inline]
+    fn value_with_increment(&self, phase: f32, _increment: f32) -> f32 {
+        self.value_at_phase(phase)
+    }
+
+    /// Noise waveforms are generated from the oscillator's own noise state instead of the phase.
+    #[inline]
+    fn noise(&self) -> Option<NoiseColor> {
+        None
+    }
 }
 
 
@@ -38,7 +140,36 @@ impl Waveform for Dynamic {
             Dynamic::Saw => Saw.value_at_phase(phase),
             Dynamic::Square => Square.value_at_phase(phase),
             Dynamic::SawExp2 => SawExp2.value_at_phase(phase),
-            Dynamic::Random => Random.value_at_phase(phase),
+            Dynamic::BlepSaw => BlepSaw.value_at_phase(phase),
+            Dynamic::BlepSquare => BlepSquare.value_at_phase(phase),
+            Dynamic::Pulse |
+            Dynamic::TriSaw |
+            Dynamic::SawExp => self.shaped_value(phase, 0.0, DEFAULT_SHAPE),
+            Dynamic::WhiteNoise |
+            Dynamic::PinkNoise |
+            Dynamic::BrownNoise |
+            Dynamic::SampleHold => 0.0,
+        }
+    }
+
+    #[inline]
+    fn value_with_increment(&self, phase: f32, increment: f32) -> f32 {
+        match *self {
+            Dynamic::BlepSaw => BlepSaw.value_with_increment(phase, increment),
+            Dynamic::BlepSquare => BlepSquare.value_with_increment(phase, increment),
+            Dynamic::Pulse => self.shaped_value(phase, increment, DEFAULT_SHAPE),
+            _ => self.value_at_phase(phase),
+        }
+    }
+
+    #[inline]
+    fn noise(&self) -> Option<NoiseColor> {
+        match *self {
+            Dynamic::WhiteNoise => Some(NoiseColor::White),
+            Dynamic::PinkNoise => Some(NoiseColor::Pink),
+            Dynamic::BrownNoise => Some(NoiseColor::Brown),
+            Dynamic::SampleHold => Some(NoiseColor::SampleHold),
+            _ => None,
         }
     }
 }
@@ -74,11 +205,64 @@ impl Waveform for Square {
     }
 }
 
+impl Waveform for BlepSaw {
+    #[inline]
+    fn value_at_phase(&self, phase: f32) -> f32 {
+        Saw.value_at_phase(phase)
+    }
+
+    #[inline]
+    fn value_with_increment(&self, phase: f32, increment: f32) -> f32 {
+        // the falling saw jumps up at the start of the cycle
+        Saw.value_at_phase(phase) + poly_blep(phase, increment)
+    }
+}
+
+impl Waveform for BlepSquare {
+    #[inline]
+    fn value_at_phase(&self, phase: f32) -> f32 {
+        Square.value_at_phase(phase)
+    }
 
-impl Waveform for Random {
     #[inline]
-    fn value_at_phase(&self, _phase: f32) -> f32 {
-        rand::random::<f32>() * 2.0 - 1.0
+    fn value_with_increment(&self, phase: f32, increment: f32) -> f32 {
+        Square.value_at_phase(phase) +
+            poly_blep((phase + 0.5).fract(), increment) -
+            poly_blep(phase, increment)
+    }
+}
+
+impl Waveform for Pulse {
+    #[inline]
+    fn value_at_phase(&self, phase: f32) -> f32 {
+        if phase < self.width { -1.0 } else { 1.0 }
+    }
+
+    #[inline]
+    fn value_with_increment(&self, phase: f32, increment: f32) -> f32 {
+        self.value_at_phase(phase) +
+            poly_blep((phase - self.width + 1.0).fract(), increment) -
+            poly_blep(phase, increment)
+    }
+}
+
+impl Waveform for TriSaw {
+    #[inline]
+    fn value_at_phase(&self, phase: f32) -> f32 {
+        // rises until the peak, then falls for the rest of the cycle
+        let peak = 0.5 * (1.0 - self.morph);
+        if phase < peak {
+            phase / peak * 2.0 - 1.0
+        } else {
+            1.0 - (phase - peak) / (1.0 - peak) * 2.0
+        }
+    }
+}
+
+impl Waveform for SawExp {
+    #[inline]
+    fn value_at_phase(&self, phase: f32) -> f32 {
+        saw_exp(phase, self.steepness)
     }
 }
 
@@ -90,8 +274,42 @@ impl Waveform for SinSq {
     }
 }
 
+/// Polynomial correction for an upward step of 2 at phase 0: the difference between
+/// a band-limited step and the naive one, nonzero within one increment of the step.
+#[inline]
+fn poly_blep(phase: f32, increment: f32) -> f32 {
+    let dt = increment.min(0.5);
+    if dt <= 0.0 {
+        0.0
+    } else if phase < dt {
+        let t = phase / dt;
+        t + t - t * t - 1.0
+    } else if phase > 1.0 - dt {
+        let t = (phase - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
 #[inline]
 fn saw_exp(phase: f32, steepness: f32) -> f32 {
     let saw = Saw.value_at_phase(phase);
     saw * saw.abs().powf(steepness)
 }
+
+#[test]
+fn test_classic_selection_is_stable () {
+    // values stored before the variants existed still pick the same waveforms
+    let stored = [
+        (0.0, Dynamic::Sine),
+        (0.3, Dynamic::Saw),
+        (0.5, Dynamic::Square),
+        (0.7, Dynamic::SawExp2),
+        (1.0, Dynamic::WhiteNoise),
+    ];
+    for &(value, wave) in stored.iter() {
+        assert_eq!(Dynamic::from_controls(value, 0.0), wave);
+    }
+    assert_eq!(Dynamic::from_controls(0.0, 1.0), Dynamic::SampleHold);
+}
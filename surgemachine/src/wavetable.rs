@@ -0,0 +1,203 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+use wav::{self, WavError};
+use waveform::Waveform;
+
+/// Samples per frame, every source cycle is resampled to this length.
+pub const TABLE_SIZE: usize = 2048;
+// one band-limited copy per octave, down to a single harmonic
+const NUM_LEVELS: usize = 11;
+
+/// Frames of single-cycle waveforms, each stored with band-limited copies per octave.
+pub struct Wavetable {
+    num_frames: usize,
+    // frames of `TABLE_SIZE` samples laid out one after another, per level
+    levels: Vec<Vec<f32>>,
+}
+
+impl Wavetable {
+    /// Splits `samples` into cycles of `cycle_len` samples, a trailing partial cycle is dropped.
+    pub fn from_cycles (samples: &[f32], cycle_len: usize) -> Option<Wavetable> {
+        if cycle_len < 2 || samples.len() < cycle_len {
+            return None;
+        }
+        let num_frames = samples.len() / cycle_len;
+        let mut levels = vec![Vec::with_capacity(num_frames * TABLE_SIZE); NUM_LEVELS];
+        let mut re = vec![0.0; TABLE_SIZE];
+        let mut im = vec![0.0; TABLE_SIZE];
+        let mut spectrum_re = vec![0.0; TABLE_SIZE];
+        let mut spectrum_im = vec![0.0; TABLE_SIZE];
+
+        for cycle in samples.chunks(cycle_len).take(num_frames) {
+            resample_cycle(cycle, &mut spectrum_re);
+            for value in spectrum_im.iter_mut() {
+                *value = 0.0;
+            }
+            fft(&mut spectrum_re, &mut spectrum_im, false);
+
+            for (level, samples) in levels.iter_mut().enumerate() {
+                let harmonics = (TABLE_SIZE / 2 >> level).min(TABLE_SIZE / 2 - 1);
+                for bin in 0..TABLE_SIZE {
+                    let harmonic = if bin > TABLE_SIZE / 2 { TABLE_SIZE - bin } else { bin };
+                    let keep = harmonic > 0 && harmonic <= harmonics;
+                    re[bin] = if keep { spectrum_re[bin] } else { 0.0 };
+                    im[bin] = if keep { spectrum_im[bin] } else { 0.0 };
+                }
+                fft(&mut re, &mut im, true);
+                samples.extend_from_slice(&re);
+            }
+        }
+
+        Some(Wavetable {
+            num_frames: num_frames,
+            levels: levels,
+        })
+    }
+
+    /// Loads a table from a WAV file. Files without a cycle length are cut into frames
+    /// of `TABLE_SIZE` when they divide evenly, and are taken as a single cycle otherwise.
+    pub fn from_wav (data: &[u8]) -> Result<Wavetable, WavError> {
+        let wav = wav::read_wav(data)?;
+        let len = wav.samples.len();
+        let cycle_len = match wav.cycle_len {
+            Some(cycle_len) => cycle_len,
+            None if len >= TABLE_SIZE && len % TABLE_SIZE == 0 => TABLE_SIZE,
+            None => len,
+        };
+        Wavetable::from_cycles(&wav.samples, cycle_len).ok_or(WavError::MissingData)
+    }
+
+    pub fn num_frames (&self) -> usize {
+        self.num_frames
+    }
+
+    /// Full bandwidth frames, enough to rebuild the table with `from_cycles`.
+    pub fn frames (&self) -> &[f32] {
+        &self.levels[0]
+    }
+
+    /// `position` morphs across frames from 0.0 to 1.0. The octave copy is picked so
+    /// that no harmonic exceeds nyquist at the given phase increment.
+    #[inline]
+    pub fn value (&self, position: f32, phase: f32, increment: f32) -> f32 {
+        let octaves = (increment * TABLE_SIZE as f32).max(1.0).log2().ceil();
+        let level = &self.levels[(octaves as usize).min(NUM_LEVELS - 1)];
+
+        let frame = position.max(0.0).min(1.0) * (self.num_frames - 1) as f32;
+        let index = frame.floor() as usize;
+        let next = (index + 1).min(self.num_frames - 1);
+        let a = frame_value(&level[index * TABLE_SIZE..(index + 1) * TABLE_SIZE], phase);
+        if next == index {
+            return a;
+        }
+        let b = frame_value(&level[next * TABLE_SIZE..(next + 1) * TABLE_SIZE], phase);
+        a + (b - a) * (frame - index as f32)
+    }
+}
+
+#[inline]
+fn frame_value (frame: &[f32], phase: f32) -> f32 {
+    let position = phase * TABLE_SIZE as f32;
+    let index = position.floor() as usize % TABLE_SIZE;
+    let next = (index + 1) % TABLE_SIZE;
+    let frac = position - position.floor();
+    frame[index] + (frame[next] - frame[index]) * frac
+}
+
+/// Linear interpolation of one cycle to `TABLE_SIZE` samples.
+fn resample_cycle (cycle: &[f32], out: &mut [f32]) {
+    let len = cycle.len();
+    for (i, value) in out.iter_mut().enumerate() {
+        let position = i as f32 * len as f32 / TABLE_SIZE as f32;
+        let index = position.floor() as usize % len;
+        let next = (index + 1) % len;
+        let frac = position - position.floor();
+        *value = cycle[index] + (cycle[next] - cycle[index]) * frac;
+    }
+}
+
+/// In place radix-2 transform, the length must be a power of two.
+fn fft (re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let half = len / 2;
+        let angle = sign * 2.0 * PI / len as f32;
+        let mut start = 0;
+        while start < n {
+            for k in 0..half {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let a = start + k;
+                let b = a + half;
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+            start += len;
+        }
+        len <<= 1;
+    }
+
+    if inverse {
+        let scale = 1.0 / n as f32;
+        for (re, im) in re.iter_mut().zip(im.iter_mut()) {
+            *re *= scale;
+            *im *= scale;
+        }
+    }
+}
+
+/// Wavetable playback, silent until a table is assigned.
+#[derive(Clone, Default)]
+pub struct TableWave {
+    pub table: Option<Arc<Wavetable>>,
+    pub position: f32,
+}
+
+impl Waveform for TableWave {
+    #[inline]
+    fn value_at_phase(&self, phase: f32) -> f32 {
+        self.value_with_increment(phase, 0.0)
+    }
+
+    #[inline]
+    fn value_with_increment(&self, phase: f32, increment: f32) -> f32 {
+        match self.table {
+            Some(ref table) => table.value(self.position, phase, increment),
+            None => 0.0,
+        }
+    }
+}
+
+#[test]
+fn test_band_limited_levels () {
+    let mut saw: Vec<f32> = (0..100).map(|i| 1.0 - 2.0 * i as f32 / 100.0).collect();
+    saw[0] = 0.0;
+    let table = Wavetable::from_cycles(&saw, saw.len()).unwrap();
+    assert_eq!(table.num_frames(), 1);
+
+    // above a quarter cycle per sample only the fundamental fits below nyquist
+    let increment = 0.4;
+    for &phase in [0.0, 0.125, 0.25, 0.5, 0.75].iter() {
+        let fundamental = 2.0 / PI * (2.0 * PI * phase).sin();
+        assert!((table.value(0.0, phase, increment) - fundamental).abs() < 0.02);
+    }
+}